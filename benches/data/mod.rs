@@ -0,0 +1,140 @@
+//! Generated data sets in the shapes the tool reads, the same on every run
+
+// each benchmark uses some of them
+#![allow(dead_code)]
+
+const TLDS: [&str; 6] = ["com", "net", "org", "io", "co.uk", "de"];
+const PREFIXES: [&str; 6] = ["ads", "tracker", "metrics", "cdn", "pixel", "t"];
+const QTYPES: [&str; 4] = ["A", "AAAA", "HTTPS", "A"];
+
+/// Linear congruential generator, good enough to spread the names
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random(seed)
+    }
+
+    pub fn next(&mut self) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) as usize
+    }
+
+    fn pick<'t>(&mut self, from: &[&'t str]) -> &'t str {
+        from[self.next() % from.len()]
+    }
+
+    /// A name out of `distinct` registrable domains, with up to two more labels
+    /// so that some of the names are subdomains of others
+    pub fn domain(&mut self, distinct: usize) -> String {
+        let registrable = format!("d{}.{}", self.next() % distinct, self.pick(&TLDS));
+        match self.next() % 4 {
+            0 => registrable,
+            1 => format!("{}.{}", self.pick(&PREFIXES), registrable),
+            _ => format!(
+                "{}{}.{}.{}",
+                self.pick(&PREFIXES),
+                self.next() % 50,
+                self.pick(&PREFIXES),
+                registrable
+            ),
+        }
+    }
+}
+
+/// A hosts file, 0.0.0.0 or 127.0.0.1 and a name, with comments
+pub fn hosts(lines: usize) -> String {
+    let mut random = Random::new(1);
+    let mut list = String::with_capacity(lines * 40);
+    for _ in 0..lines {
+        match random.next() % 20 {
+            0 => list.push_str("# a comment\n"),
+            1..=3 => list.push_str(&format!("127.0.0.1 {}\n", random.domain(lines / 4))),
+            _ => list.push_str(&format!("0.0.0.0 {}\n", random.domain(lines / 4))),
+        }
+    }
+    list
+}
+
+/// A list with just a name on each line, some in upper case
+pub fn bare(lines: usize) -> String {
+    let mut random = Random::new(2);
+    let mut list = String::with_capacity(lines * 32);
+    for _ in 0..lines {
+        let domain = random.domain(lines / 4);
+        match random.next() % 10 {
+            0 => list.push_str(&format!("{}\n", domain.to_uppercase())),
+            _ => list.push_str(&format!("{}\n", domain)),
+        }
+    }
+    list
+}
+
+/// Adblock Plus rules, the form of many upstream lists, with element hiding and
+/// exception rules. They are not plain names, the parser refuses them
+pub fn abp(lines: usize) -> String {
+    let mut random = Random::new(3);
+    let mut list = String::with_capacity(lines * 40);
+    list.push_str("[Adblock Plus 2.0]\n");
+    for _ in 1..lines {
+        let domain = random.domain(lines / 4);
+        match random.next() % 10 {
+            0 => list.push_str("! a comment\n"),
+            1 => list.push_str(&format!("@@||{}^$document\n", domain)),
+            2 => list.push_str(&format!("{}##.banner\n", domain)),
+            3 => list.push_str(&format!("||{}^$third-party\n", domain)),
+            _ => list.push_str(&format!("||{}^\n", domain)),
+        }
+    }
+    list
+}
+
+/// The concatenated list written by getlists.sh: hosts and bare lines, comments,
+/// the headers of the upstream lists and subdomains of blocked domains
+pub fn concatenated(lines: usize) -> String {
+    let mut random = Random::new(42);
+    let mut list = String::with_capacity(lines * 32);
+    for i in 0..lines {
+        if i % 100_000 == 0 {
+            list.push_str(&format!("# dns-block: https://lists.example/{}\n", i));
+            continue;
+        }
+        let domain = format!("d{}.{}", random.next() % (lines / 4), random.pick(&TLDS));
+        match random.next() % 10 {
+            0 => list.push_str("# a comment\n"),
+            1..=4 => list.push_str(&format!("0.0.0.0 ads{}.{}\n", random.next() % 50, domain)),
+            5 => list.push_str(&format!("127.0.0.1 Tracker.{}\n", domain)),
+            _ => list.push_str(&format!("{}\n", domain)),
+        }
+    }
+    list
+}
+
+/// A Bind9 query log, a quarter of the queries are for names like the ones
+/// of a hosts list of `hosts_lines`, many of them blocked
+pub fn query_log(lines: usize, hosts_lines: usize) -> String {
+    let mut listed = Random::new(1);
+    let mut random = Random::new(4);
+    let mut log = String::with_capacity(lines * 140);
+    for i in 0..lines {
+        let name = match random.next() % 4 {
+            0 => listed.domain(hosts_lines / 4),
+            _ => format!("www.site{}.{}", random.next() % 10_000, random.pick(&TLDS)),
+        };
+        log.push_str(&format!(
+            "20-Jan-2021 10:{:02}:{:02}.{:03} client @0x7f3a5c0e1b68 10.0.0.{}#{} ({}): view internal: query: {} IN {} + (10.0.0.12)\n",
+            i / 60_000 % 60,
+            i / 1000 % 60,
+            i % 1000,
+            random.next() % 30 + 2,
+            random.next() % 60_000 + 1024,
+            name,
+            name,
+            random.pick(&QTYPES),
+        ));
+    }
+    log
+}
@@ -0,0 +1,85 @@
+//! Reading and folding a synthetic 2M line list with an internationalized name in it,
+//! cut in chunks parsed with one thread and with all of them, and a line at a time on
+//! one thread as it was read before the chunks
+//!
+//! cargo bench --bench parse
+//!
+//! See benches/stages.rs for comparing against a saved baseline.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use dns_block::blocklist::process_baddies;
+use dns_block::public_suffix::PublicSuffixList;
+use dns_block::sub_domains::{normalize_line, validate, Domain};
+use dns_block::BlockListBuilder;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::time::Duration;
+
+mod data;
+
+const LINES: usize = 2_000_000;
+
+fn build(list: String) -> usize {
+    let builder = BlockListBuilder::new().block_source("synthetic", list);
+    let blocklist = builder.build().unwrap();
+    blocklist.len()
+}
+
+/// One non-ASCII name sent the whole text through the conversion, then it was
+/// lowercased and parsed a line at a time
+fn build_serial(list: String) -> usize {
+    let mut text = if list.is_ascii() {
+        list
+    } else {
+        list.lines()
+            .map(normalize_line)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    text.make_ascii_lowercase();
+    let public_suffixes = PublicSuffixList::default();
+    let domains: Vec<Domain> = text
+        .lines()
+        .filter_map(Domain::new)
+        .filter(|d| validate(d.name).is_ok() && !public_suffixes.is_public_suffix(d.name))
+        .collect();
+    let (index, _) = process_baddies(
+        std::iter::once(domains.as_slice()),
+        &FnvHashSet::default(),
+        &FnvHashMap::default(),
+        &public_suffixes,
+        |_| true,
+    );
+    index.blocked.len()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut list = data::concatenated(LINES);
+    list.insert_str(list.len() / 2, "0.0.0.0 bücher.example\n");
+    assert_eq!(build_serial(list.clone()), build(list.clone()));
+    let single = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("build 2M lines");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+    group.bench_function("a line at a time", |b| {
+        b.iter_batched(|| list.clone(), build_serial, BatchSize::LargeInput)
+    });
+    group.bench_function("chunks, 1 thread", |b| {
+        b.iter_batched(
+            || list.clone(),
+            |list| single.install(|| build(list)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function(
+        format!("chunks, {} threads", rayon::current_num_threads()),
+        |b| b.iter_batched(|| list.clone(), build, BatchSize::LargeInput),
+    );
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
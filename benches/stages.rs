@@ -0,0 +1,169 @@
+//! The stages of building a block list and of filtering a query log, each on
+//! generated data: parsing list lines, walking the parents of a name, folding the
+//! domains into the index, writing the formats and looking up the queries of pipe
+//!
+//! cargo bench --bench stages
+//!
+//! To catch performance changes before a release, save a baseline on the commit of
+//! the last release, then compare the candidate against it, criterion reports
+//! which benchmarks changed and by how much:
+//!
+//! cargo bench -- --save-baseline release
+//! cargo bench -- --baseline release
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dns_block::blocklist::process_baddies;
+use dns_block::client_filter::ClientFilter;
+use dns_block::filter::{self, OutputFormat};
+use dns_block::packed::PackedIndex;
+use dns_block::prefilter::Prefiltered;
+use dns_block::public_suffix::PublicSuffixList;
+use dns_block::sub_domains::{sub_domain_iterator, Domain};
+use dns_block::{BlockListBuilder, Format};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::hint::black_box;
+use std::io;
+
+mod data;
+
+const LINES: usize = 200_000;
+const QUERIES: usize = 100_000;
+
+fn domain_new(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Domain::new");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for (name, list) in [
+        ("hosts", data::hosts(LINES)),
+        ("bare", data::bare(LINES)),
+        ("abp", data::abp(LINES)),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| list.lines().filter_map(Domain::new).count())
+        });
+    }
+    group.finish();
+}
+
+fn sub_domains(c: &mut Criterion) {
+    let list = data::bare(LINES).to_lowercase();
+    let mut group = c.benchmark_group("sub_domain_iterator");
+    group.throughput(Throughput::Elements(LINES as u64));
+    group.bench_function("bare", |b| {
+        b.iter(|| {
+            list.lines()
+                .map(|name| sub_domain_iterator(name, 1).count())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn fold(c: &mut Criterion) {
+    let list = data::hosts(LINES);
+    let domains: Vec<Domain> = list.lines().filter_map(Domain::new).collect();
+    let mut whitelist = FnvHashSet::default();
+    whitelist.extend(["d1.com", "d2.net", "tracker.d3.org"]);
+    let typed_whitelist = FnvHashMap::default();
+    let public_suffixes = PublicSuffixList::default();
+
+    let mut group = c.benchmark_group("process_baddies");
+    group.throughput(Throughput::Elements(domains.len() as u64));
+    group.bench_function("hosts", |b| {
+        b.iter(|| {
+            let (index, _) = process_baddies(
+                std::iter::once(domains.as_slice()),
+                &whitelist,
+                &typed_whitelist,
+                &public_suffixes,
+                |_| true,
+            );
+            index.blocked.len()
+        })
+    });
+    group.finish();
+}
+
+fn writers(c: &mut Criterion) {
+    let builder = BlockListBuilder::new().block_source("hosts", data::hosts(LINES));
+    let blocklist = builder.build().unwrap();
+
+    let mut group = c.benchmark_group("writers");
+    group.throughput(Throughput::Elements(blocklist.len() as u64));
+    for format in [Format::Plain, Format::Bind, Format::Unbound, Format::Packed] {
+        let mut out = Vec::new();
+        group.bench_function(format.to_string(), |b| {
+            b.iter(|| {
+                out.clear();
+                blocklist.write(format, &mut out).unwrap();
+                out.len()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pipe(c: &mut Criterion) {
+    let builder = BlockListBuilder::new().block_source("hosts", data::hosts(LINES));
+    let blocklist = builder.build().unwrap();
+    let mut packed = Vec::new();
+    blocklist.write(Format::Packed, &mut packed).unwrap();
+    let index = PackedIndex::from_bytes(packed).unwrap();
+    let log = data::query_log(QUERIES, LINES);
+    let clients = ClientFilter::new(None, None).unwrap();
+
+    let run = |lookup: &dyn Fn(&mut &[u8]) -> dns_block::Result<()>| {
+        let mut input = black_box(log.as_bytes());
+        lookup(&mut input).unwrap()
+    };
+
+    let mut group = c.benchmark_group("pipe");
+    group.throughput(Throughput::Elements(QUERIES as u64));
+    for output in [OutputFormat::Text, OutputFormat::Json] {
+        group.bench_function(format!("lists {}", output), |b| {
+            b.iter(|| {
+                run(&|input| {
+                    filter::filter_lines(&blocklist, &clients, output, input, &mut io::sink())
+                })
+            })
+        });
+    }
+    group.bench_function("packed json", |b| {
+        b.iter(|| {
+            run(&|input| {
+                filter::filter_lines(&index, &clients, OutputFormat::Json, input, &mut io::sink())
+            })
+        })
+    });
+    let prefiltered = Prefiltered::new(&blocklist, 10);
+    group.bench_function("prefiltered lists json", |b| {
+        b.iter(|| {
+            run(&|input| {
+                filter::filter_lines(
+                    &prefiltered,
+                    &clients,
+                    OutputFormat::Json,
+                    input,
+                    &mut io::sink(),
+                )
+            })
+        })
+    });
+    let prefiltered = Prefiltered::new(&index, 10);
+    group.bench_function("prefiltered packed json", |b| {
+        b.iter(|| {
+            run(&|input| {
+                filter::filter_lines(
+                    &prefiltered,
+                    &clients,
+                    OutputFormat::Json,
+                    input,
+                    &mut io::sink(),
+                )
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, domain_new, sub_domains, fold, writers, pipe);
+criterion_main!(benches);
@@ -9,12 +9,12 @@ set -o pipefail
 
 DEBUG=$1
 if [[ "${DEBUG}" == "debug" ]]; then
-  echo "Debug mode, will fetch each source in separate files."
-else
-  OUT=concatenated.list
-  rm -f $OUT
-  echo "Building $OUT"
+  echo "Debug mode, will only fetch the lists."
 fi
+# each source is saved in its own file, dns-block reads them all with lists.d/*.list
+LISTS=lists.d
+rm -rf $LISTS.new
+mkdir -p $LISTS.new
 
 # Extracts the URL from a line by removing # comments and trimming
 # $1 - line
@@ -35,7 +35,7 @@ function extract_url {
   LIST=$var
 }
 
-# Creates a file name out of the URL to save the content for debug
+# Creates a file name out of the URL to save the content
 # removes the https:// and replaces /, &, ? with _
 # $1 - url
 # return - global $OUT
@@ -44,30 +44,21 @@ function make_file_name {
   F=${F//\//_}
   F=${F//&/_}
   F=${F//\?/_}
-  OUT=${F/%.txt/}.hosts
+  OUT=$LISTS.new/${F/%.txt/}.list
 }
 
-# Fetches the content from a list of lists
+# Fetches the content from a list of lists, one file per list
 # $1 file name of list of lists
-# result - if debug, individual files per list, else $OUT
+# result - the files in $LISTS.new
 function fetch_list_of_lists {
   while read f
   do
     extract_url "$f"
     if [[ ! -z "$LIST" ]]; then
       echo "Fetching: $LIST"
-      if [[ "${DEBUG}" == "debug" ]]; then
-        make_file_name $f    
-        rm -f ${OUT}
-      else
-        cat >> $OUT <<FILEHEADER
-
-#----------------------------------------------------- 
-# dns-block: $LIST
-#-----------------------------------------------------
-
-FILEHEADER
-      fi
+      make_file_name "$LIST"
+      # the header credits the entries to the URL rather than to the file name
+      echo "# dns-block: $LIST" > "${OUT}"
       curl --insecure --fail --max-time 10 --retry 10 --retry-delay 0 "$LIST" >> "${OUT}"
     fi
   done < $1
@@ -76,7 +67,20 @@ FILEHEADER
 fetch_list_of_lists list_of_lists.txt
 fetch_list_of_lists own_list_of_lists.txt
 
+# the lists are only replaced once they were all fetched
+rm -rf $LISTS.old
+if [[ -d $LISTS ]]; then
+  mv $LISTS $LISTS.old
+fi
+mv $LISTS.new $LISTS
+rm -rf $LISTS.old
+
+# entries that are public suffixes, e.g. co.uk, are not blocked
+PSL=public_suffix_list.dat
+echo "Fetching: $PSL"
+curl --fail --max-time 10 --retry 10 --retry-delay 0 -o $PSL.new https://publicsuffix.org/list/$PSL && mv $PSL.new $PSL
+
 if [[ ! "${DEBUG}" == "debug" ]]; then
-  ./dns-block -dd $OUT domains.whitelisted hosts_blocked.txt pack domains.blocked
-  ./dns-block $OUT domains.whitelisted hosts_blocked.txt pack --bind rpz.db
+  # writes all the outputs listed in dns-block.toml in one go
+  ./dns-block -dd --config dns-block.toml build
 fi
@@ -0,0 +1,202 @@
+use crate::client_filter::ClientFilter;
+use crate::domain_set::DomainSet;
+use crate::error::{Error, Result};
+use crate::filter::{parse_query, Lookup, QueryResult};
+use crate::index::Index;
+use crate::packed::PackedIndex;
+use crate::qtype::QTypes;
+use crate::statistics::AuditStatistics;
+use crate::sub_domains::Domain;
+use crate::writers::Format;
+use std::io::{self, BufRead};
+
+/// The block list currently in use, as written by pack in any of the formats
+pub enum Baseline<'a> {
+    Index(Index<'a>),
+    Packed(PackedIndex<&'a [u8]>),
+}
+
+impl Baseline<'_> {
+    /// Reads a block list written by pack, the format is told from the content
+    pub fn parse<'a>(path: &str, data: &'a [u8]) -> Result<Baseline<'a>> {
+        if data.starts_with(b"DNSBLOCK") {
+            let index = PackedIndex::from_bytes(data).map_err(|e| Error::input(path, e))?;
+            return Ok(Baseline::Packed(index));
+        }
+        let text = std::str::from_utf8(data).map_err(|_| {
+            Error::InvalidInput(format!(
+                "{}: not a block list written by pack, neither text nor a packed index",
+                path
+            ))
+        })?;
+        Ok(Baseline::Index(baseline_index(text)))
+    }
+
+    fn is_blocked(&self, domain: &str, qtype: Option<&str>) -> bool {
+        match self {
+            Baseline::Index(index) => index.find_blocking(domain, qtype).is_some(),
+            Baseline::Packed(index) => index.check(domain, qtype).0 == QueryResult::Blocked,
+        }
+    }
+}
+
+/// The text format of a block list, from its first line that is not a comment
+fn text_format(content: &str) -> Format {
+    let first = content
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'));
+    match first {
+        Some(l) if l.starts_with("$TTL") || l.ends_with(" CNAME .") => Format::Bind,
+        Some(l) if l.starts_with("local-zone:") => Format::Unbound,
+        _ => Format::Plain,
+    }
+}
+
+/// Makes an index of a block list in one of the text formats written by pack
+pub fn baseline_index(content: &str) -> Index<'_> {
+    let format = text_format(content);
+    let mut index = Index::new(DomainSet::default());
+    let mut blocked = Vec::with_capacity(content.len() / 16);
+    for line in content.lines() {
+        match format {
+            // the subdomains have a *. record of their own
+            Format::Bind => {
+                if let Some(name) = line.trim_end().strip_suffix(" CNAME .") {
+                    if !name.starts_with("*.") {
+                        blocked.push((name, 0));
+                    }
+                }
+            }
+            Format::Unbound => {
+                let mut tokens = line.split_whitespace();
+                let kind = tokens.next();
+                let name = tokens
+                    .next()
+                    .map(|t| t.trim_matches('"').trim_end_matches('.'));
+                match (kind, name) {
+                    (Some("local-zone:"), Some(name)) => match tokens.next() {
+                        Some("always_nxdomain") => blocked.push((name, 0)),
+                        Some("typetransparent") => {
+                            index.typed_blocked.insert(name, (QTypes::default(), 0));
+                        }
+                        _ => (),
+                    },
+                    // local-data: "name. 60 IN A 0.0.0.0"
+                    (Some("local-data:"), Some(name)) => {
+                        let qtype = tokens.nth(2).and_then(|t| QTypes::parse(t).ok());
+                        if let (Some(qtype), Some((types, _))) =
+                            (qtype, index.typed_blocked.get_mut(name))
+                        {
+                            *types = types.union(qtype);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ => {
+                if let Some(domain) = Domain::new(line) {
+                    blocked.push((domain.name, 0));
+                }
+            }
+        }
+    }
+    index.blocked = blocked.into_iter().collect();
+    index
+}
+
+/// Replays a query log against the new indexes without serving anything and counts
+/// the queries whose outcome changes compared to the baseline. Without a baseline
+/// every logged query is taken as allowed.
+pub fn audit(
+    log: impl BufRead,
+    blocklist: &impl Lookup,
+    baseline: Option<&Baseline>,
+    client_filter: &ClientFilter,
+    top: usize,
+) -> io::Result<AuditStatistics> {
+    let mut statistics = AuditStatistics::new(top);
+    for line in log.lines() {
+        let line = line?;
+        if let Some(query) = parse_query(&line) {
+            if client_filter.matches(query.client) {
+                let domain = query.name.to_ascii_lowercase();
+                let (result, _) = blocklist.check(&domain, query.qtype);
+                let was_blocked = baseline
+                    .map(|b| b.is_blocked(&domain, query.qtype))
+                    .unwrap_or(false);
+                statistics.increment(
+                    query.client,
+                    &domain,
+                    was_blocked,
+                    result == QueryResult::Blocked,
+                );
+            }
+        }
+    }
+    Ok(statistics)
+}
+
+#[cfg(test)]
+mod tests_audit {
+    use super::*;
+    use crate::blocklist::BlockListBuilder;
+
+    #[test]
+    fn audit_test() {
+        let log = "\
+20-Jan-2021 10:10:10.536 client 10.0.0.30#7216 (a.ads.fb.com): query: a.ads.fb.com IN A + (10.0.0.12)
+20-Jan-2021 10:10:11.536 client 10.0.0.30#7216 (a.ads.fb.com): query: a.ads.fb.com IN A + (10.0.0.12)
+20-Jan-2021 10:10:12.536 client 10.0.0.31#7216 (tracker.com): query: tracker.com IN A + (10.0.0.12)
+20-Jan-2021 10:10:13.536 client 10.0.0.31#7216 (old.com): query: old.com IN A + (10.0.0.12)
+";
+        let builder = BlockListBuilder::new()
+            .block_source("candidate", "ads.fb.com\ntracker.com\n".to_string());
+        let blocklist = builder.build().unwrap();
+        let baseline = super::Baseline::Index(super::baseline_index("tracker.com\nold.com\n"));
+
+        let statistics = super::audit(
+            log.as_bytes(),
+            &blocklist,
+            Some(&baseline),
+            &ClientFilter::default(),
+            10,
+        )
+        .unwrap();
+        let report = format!("{}", statistics);
+        assert!(report.contains("Allowed -> blocked:                2"));
+        assert!(report.contains("Distinct domains:                  1"));
+        assert!(report.contains("Blocked -> allowed:                1"));
+        assert!(report.contains("a.ads.fb.com"));
+        assert!(!report.contains("tracker.com"));
+    }
+
+    #[test]
+    fn baseline_formats_test() {
+        let builder = BlockListBuilder::new().block_source(
+            "baseline",
+            "tracker.com\nold.com\nfb.org $type=HTTPS\n".to_string(),
+        );
+        let blocklist = builder.build().unwrap();
+        for format in [Format::Bind, Format::Unbound, Format::Packed, Format::Plain] {
+            let mut data = Vec::new();
+            blocklist.write(format, &mut data).unwrap();
+            let baseline = Baseline::parse("baseline", &data).unwrap();
+            assert!(baseline.is_blocked("old.com", Some("A")), "{format}");
+            assert!(baseline.is_blocked("www.tracker.com", None), "{format}");
+            assert!(!baseline.is_blocked("new.com", None), "{format}");
+            // bind and plain have no record type specific entries
+            let typed = matches!(format, Format::Unbound | Format::Packed);
+            assert_eq!(
+                typed,
+                baseline.is_blocked("fb.org", Some("HTTPS")),
+                "{format}"
+            );
+            assert!(!baseline.is_blocked("fb.org", Some("A")), "{format}");
+        }
+        assert!(matches!(
+            Baseline::parse("baseline", &[0xff, 0xfe, 0]),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,1314 @@
+//! Building the index of blocked domains out of block lists and whitelists
+
+use crate::compression::{self, Compression};
+use crate::dns_resolver;
+use crate::domain_set::{DomainSet, Insert};
+use crate::error::{Error, Result};
+use crate::filter::{Lookup, QueryResult};
+use crate::index::{shard, Index};
+use crate::inputs;
+use crate::output;
+use crate::public_suffix::PublicSuffixList;
+use crate::qtype::QTypes;
+use crate::state::{self, State};
+use crate::statistics::Statistics;
+use crate::sub_domains::{
+    cmp_labels, labels, normalize_line, normalize_name, sub_domain_iterator, validate, Domain,
+};
+use crate::writers::{self, Format, Order};
+use fnv::FnvHashMap as HashMap;
+use fnv::FnvHashSet as HashSet;
+use log::*;
+use rayon::prelude::*;
+use std::fmt;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Header written by getlists.sh in front of each upstream list
+pub(crate) const SOURCE_HEADER: &str = "# dns-block: ";
+/// Size of the pieces of the lists that are lowercased and parsed in parallel
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A named list of domains, one per line, in hosts or plain format
+struct Source {
+    name: String,
+    text: String,
+    /// where the source headers of the upstream lists start, but at the start of the text
+    headers: Vec<usize>,
+}
+
+impl Source {
+    /// Lowercases the text and finds its source headers in pieces of whole lines, in
+    /// parallel, the pieces with internationalized names are converted on their own
+    fn new(name: String, mut text: String) -> Source {
+        let mut pieces = Vec::with_capacity(text.len() / CHUNK_SIZE + 1);
+        let mut rest = text.as_mut_str();
+        while !rest.is_empty() {
+            let (piece, tail) = rest.split_at_mut(line_end(rest, CHUNK_SIZE));
+            pieces.push(piece);
+            rest = tail;
+        }
+        let pieces: Vec<(usize, Option<String>, Vec<usize>)> = pieces
+            .into_par_iter()
+            .map(|piece| {
+                // queries come in the A-label form, line numbers are kept for the diagnostics
+                let mut converted = (!piece.is_ascii()).then(|| {
+                    let lines: Vec<_> = piece.split('\n').map(normalize_line).collect();
+                    lines.join("\n")
+                });
+                // converting to lowercase might generate some duplicates
+                match &mut converted {
+                    Some(converted) => converted.make_ascii_lowercase(),
+                    None => piece.make_ascii_lowercase(),
+                }
+                let headers = header_starts(converted.as_deref().unwrap_or(piece));
+                (piece.len(), converted, headers)
+            })
+            .collect();
+
+        if pieces.iter().any(|(_, converted, _)| converted.is_some()) {
+            let mut joined = String::with_capacity(text.len() + text.len() / 8);
+            let mut at = 0;
+            for (len, converted, _) in &pieces {
+                joined.push_str(converted.as_deref().unwrap_or(&text[at..at + len]));
+                at += len;
+            }
+            text = joined;
+        }
+        let mut headers = Vec::new();
+        let mut at = 0;
+        for (len, converted, piece_headers) in pieces {
+            headers.extend(piece_headers.iter().map(|h| at + h).filter(|h| *h > 0));
+            at += converted.map_or(len, |c| c.len());
+        }
+        Source {
+            name,
+            text,
+            headers,
+        }
+    }
+
+    /// Cuts the text in chunks that are parsed in parallel, a chunk ends at the end
+    /// of a line and before a source header, so all its entries come from the same source.
+    /// The name of the list and of the upstream lists in its headers are added to the sources
+    fn chunks<'a>(&'a self, list: usize, sources: &mut Vec<String>) -> Vec<Chunk<'a>> {
+        sources.push(self.name.clone());
+        let mut section_starts = vec![0];
+        section_starts.extend_from_slice(&self.headers);
+
+        let mut chunks = Vec::with_capacity(self.text.len() / CHUNK_SIZE + section_starts.len());
+        for (i, start) in section_starts.iter().enumerate() {
+            let end = section_starts
+                .get(i + 1)
+                .copied()
+                .unwrap_or(self.text.len());
+            let mut rest = &self.text[*start..end];
+            if let Some(url) = rest
+                .lines()
+                .next()
+                .and_then(|l| l.strip_prefix(SOURCE_HEADER))
+            {
+                sources.push(url.trim().to_string());
+            }
+            while !rest.is_empty() {
+                let cut = line_end(rest, CHUNK_SIZE);
+                chunks.push(Chunk {
+                    file: &self.name,
+                    list,
+                    source: sources.len() - 1,
+                    text: &rest[..cut],
+                });
+                rest = &rest[cut..];
+            }
+        }
+        chunks
+    }
+}
+
+/// The end of the line at size bytes into the text, or the end of the text
+fn line_end(text: &str, size: usize) -> usize {
+    text.as_bytes()[size.min(text.len())..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(text.len(), |i| size + i + 1)
+}
+
+/// Where the source headers start in whole lines of a list
+fn header_starts(text: &str) -> Vec<usize> {
+    text.match_indices(SOURCE_HEADER)
+        .map(|(i, _)| i)
+        .filter(|i| *i == 0 || text.as_bytes()[i - 1] == b'\n')
+        .collect()
+}
+
+/// Whole lines of a block list, all from the same source
+struct Chunk<'a> {
+    file: &'a str,
+    /// index of the block list
+    list: usize,
+    source: usize,
+    text: &'a str,
+}
+
+/// The domains of a chunk by shard, with the entries left out of them
+pub(crate) struct Parsed<'a> {
+    /// index of the block list
+    pub(crate) list: usize,
+    pub(crate) lines: usize,
+    pub(crate) domains: Vec<Vec<Domain<'a>>>,
+    /// entries refused for being public suffixes, with their source
+    pub(crate) public_suffixes: Vec<(&'a str, usize)>,
+    /// line numbers relative to the start of the chunk
+    pub(crate) rejected: Vec<Rejection<'a>>,
+}
+
+impl Parsed<'_> {
+    pub(crate) fn new(list: usize, shards: usize, capacity: usize) -> Self {
+        Parsed {
+            list,
+            lines: 0,
+            domains: (0..shards)
+                .map(|_| Vec::with_capacity(capacity / shards + 1))
+                .collect(),
+            public_suffixes: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Chunk<'a> {
+    fn parse(&self, shards: usize, public_suffixes: &PublicSuffixList) -> Parsed<'a> {
+        let mut parsed = Parsed::new(self.list, shards, self.text.len() / 24);
+        for (n, line) in self.text.lines().enumerate() {
+            parsed.lines += 1;
+            if line.starts_with(SOURCE_HEADER) {
+                continue;
+            }
+            if let Some(mut domain) = parse_line(self.file, n + 1, line, &mut parsed.rejected) {
+                domain.source = self.source;
+                if public_suffixes.is_public_suffix(domain.name) {
+                    parsed.public_suffixes.push((domain.name, domain.source));
+                } else {
+                    parsed.domains[shard(domain.name, shards)].push(domain);
+                }
+            }
+        }
+        parsed
+    }
+}
+
+/// An input line that is not a valid domain name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection<'a> {
+    /// name of the file or list the line was read from
+    pub file: &'a str,
+    pub line_number: usize,
+    pub line: &'a str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Rejection<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.file, self.line_number, self.reason, self.line
+        )
+    }
+}
+
+/// Extracts the domain from a list line, lines with a name that is not valid or
+/// an unknown record type are added to the rejected ones
+pub(crate) fn parse_line<'a>(
+    file: &'a str,
+    line_number: usize,
+    line: &'a str,
+    rejected: &mut Vec<Rejection<'a>>,
+) -> Option<Domain<'a>> {
+    let reason = match Domain::parse(line) {
+        Ok(Some(domain)) => match validate(domain.name) {
+            Ok(()) => return Some(domain),
+            Err(reason) => reason,
+        },
+        Ok(None) => return None,
+        Err(reason) => reason,
+    };
+    rejected.push(Rejection {
+        file,
+        line_number,
+        line,
+        reason,
+    });
+    None
+}
+
+/// Collects the block lists and whitelists a [`BlockList`] is built from
+///
+/// ```no_run
+/// use dns_block::{BlockListBuilder, Format};
+///
+/// let builder = BlockListBuilder::new()
+///     .block_file("hosts_blocked.txt")?
+///     .block_file("concatenated.list")?
+///     .whitelist_file("domains.whitelisted")?;
+/// let blocklist = builder.build()?;
+/// assert!(blocklist.is_blocked("ads.example.com"));
+/// blocklist.write_file(Format::Plain, "simple.blocked", 3)?;
+/// # Ok::<(), dns_block::Error>(())
+/// ```
+#[derive(Default)]
+pub struct BlockListBuilder {
+    block_sources: Vec<Source>,
+    whitelist_sources: Vec<Source>,
+    resolve_cnames: bool,
+    resolver_server: Option<String>,
+    resolver_timeout: Option<Duration>,
+    public_suffixes: PublicSuffixList,
+    shards: Option<usize>,
+    state_dir: Option<String>,
+}
+
+impl BlockListBuilder {
+    pub fn new() -> BlockListBuilder {
+        BlockListBuilder::default()
+    }
+
+    /// Adds a list of domains to block, lists added first win when a domain
+    /// appears in more than one of them
+    pub fn block_source(mut self, name: impl Into<String>, text: String) -> BlockListBuilder {
+        self.block_sources.push(Source::new(name.into(), text));
+        self
+    }
+
+    /// Reads a list of domains to block from a file, compressed or not, `-` reads
+    /// the standard input
+    pub fn block_file(self, path: &str) -> Result<BlockListBuilder> {
+        let text = compression::read_to_string(path)?;
+        Ok(self.block_source(inputs::name(path), text))
+    }
+
+    /// Reads lists of domains to block from files, in parallel, they count in the
+    /// order given as if added one at a time
+    pub fn block_files(mut self, paths: &[String]) -> Result<BlockListBuilder> {
+        let sources = paths
+            .par_iter()
+            .map(|path| {
+                let text = compression::read_to_string(path)?;
+                Ok(Source::new(inputs::name(path).to_string(), text))
+            })
+            .collect::<Result<Vec<Source>>>()?;
+        self.block_sources.extend(sources);
+        Ok(self)
+    }
+
+    /// Adds a list of domains that should never be blocked, together with their parents
+    pub fn whitelist_source(mut self, name: impl Into<String>, text: String) -> BlockListBuilder {
+        self.whitelist_sources.push(Source::new(name.into(), text));
+        self
+    }
+
+    /// Reads a list of domains to whitelist from a file, compressed or not, `-` reads
+    /// the standard input
+    pub fn whitelist_file(self, path: &str) -> Result<BlockListBuilder> {
+        let text = compression::read_to_string(path)?;
+        Ok(self.whitelist_source(inputs::name(path), text))
+    }
+
+    /// Also whitelists the CNAMEs the whitelisted domains resolve to, this queries
+    /// a public DNS server, off by default
+    pub fn resolve_cnames(mut self, resolve: bool) -> BlockListBuilder {
+        self.resolve_cnames = resolve;
+        self
+    }
+
+    /// The DNS server the CNAMEs are resolved with, as address:port, 8.8.8.8:53 by default
+    pub fn resolver_server(mut self, server: impl Into<String>) -> BlockListBuilder {
+        self.resolver_server = Some(server.into());
+        self
+    }
+
+    /// How long to wait for the DNS answers, 5 seconds by default
+    pub fn resolver_timeout(mut self, timeout: Duration) -> BlockListBuilder {
+        self.resolver_timeout = Some(timeout);
+        self
+    }
+
+    /// Replaces the built in list of the most common public suffixes, entries that
+    /// are public suffixes are refused
+    pub fn public_suffixes(mut self, list: PublicSuffixList) -> BlockListBuilder {
+        self.public_suffixes = list;
+        self
+    }
+
+    /// Reads the public suffixes from a copy of the Public Suffix List
+    pub fn public_suffix_file(self, path: &str) -> Result<BlockListBuilder> {
+        Ok(self.public_suffixes(PublicSuffixList::from_file(path)?))
+    }
+
+    /// Number of parts the domains are split in and folded in parallel,
+    /// the number of cores by default
+    pub fn shards(mut self, shards: usize) -> BlockListBuilder {
+        self.shards = Some(shards.max(1));
+        self
+    }
+
+    /// Keeps what the build parsed and folded in a directory, the next build only
+    /// parses the lists that changed and folds again the shards they touch
+    pub fn state_dir(mut self, dir: impl Into<String>) -> BlockListBuilder {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Parses the lists and makes the index of blocked domains, reusing the
+    /// state of the previous build if there is a state directory
+    pub fn build(&self) -> Result<BlockList<'_>> {
+        self.build_with(self.state_dir.as_deref(), None)
+    }
+
+    /// Parses all the lists and folds all the shards, ignoring the state directory, to
+    /// compare with another build. The CNAMEs that build resolved are whitelisted again
+    /// instead of asking the DNS server, whose answers may have changed since
+    pub fn build_from_scratch(&self, other: &BlockList) -> Result<BlockList<'_>> {
+        self.build_with(None, Some(&other.cnames))
+    }
+
+    fn build_with(
+        &self,
+        state_dir: Option<&str>,
+        cnames: Option<&[String]>,
+    ) -> Result<BlockList<'_>> {
+        let start = Instant::now();
+        let shards = self
+            .shards
+            .unwrap_or_else(rayon::current_num_threads)
+            .max(1);
+        let settings = self.public_suffixes.content_hash();
+        let state = match state_dir {
+            Some(dir) => Some(State::open(dir, shards, settings)?),
+            None => None,
+        };
+        let hashes: Vec<u64> = match state {
+            Some(_) => self
+                .block_sources
+                .par_iter()
+                .map(|s| state::content_hash(&s.text))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // the whitelists come first in the sources, the block lists after them
+        let mut sources: Vec<String> = self
+            .whitelist_sources
+            .iter()
+            .map(|s| s.name.clone())
+            .collect();
+
+        // where the sources of each block list start, the list itself and then its headers
+        let mut first_sources = Vec::with_capacity(self.block_sources.len() + 1);
+        let mut reparsed = Vec::with_capacity(self.block_sources.len());
+        let mut rejected = Vec::new();
+        let (parsed, public_suffixes, cnames) = thread::scope(|scope| {
+            debug!("Do the DNS requests for whitelisted domains while we read and sort the domains we want to block");
+            let resolver = scope.spawn(move || match cnames {
+                Some(cnames) => Ok(cnames.to_vec()),
+                None => self.expand_whitelist(),
+            });
+
+            // the lists that didn't change come parsed from the state, the others in chunks
+            let mut chunks: Vec<Chunk> = Vec::new();
+            let mut parsed: Vec<Parsed> = Vec::new();
+            for (list, source) in self.block_sources.iter().enumerate() {
+                first_sources.push(sources.len());
+                let cached = state.as_ref().and_then(|state| {
+                    let loaded = state.load_list(list, &source.name, &source.text, hashes[list]);
+                    loaded.unwrap_or_else(|e| {
+                        warn!("Parsing {} again: {}", source.name, e);
+                        None
+                    })
+                });
+                match cached {
+                    Some((urls, mut cached)) => {
+                        let first_source = sources.len();
+                        sources.push(source.name.clone());
+                        sources.extend(urls);
+                        for domain in cached.domains.iter_mut().flatten() {
+                            domain.source += first_source;
+                        }
+                        for (_, source) in cached.public_suffixes.iter_mut() {
+                            *source += first_source;
+                        }
+                        parsed.push(cached);
+                    }
+                    None => chunks.extend(source.chunks(list, &mut sources)),
+                }
+                reparsed.push(parsed.last().is_none_or(|p| p.list != list));
+            }
+            first_sources.push(sources.len());
+            if state.is_some() {
+                info!(
+                    "Parsing {} of {} block lists, the others didn't change",
+                    self.block_sources.len() - parsed.len(),
+                    self.block_sources.len()
+                );
+            }
+            parsed.par_extend(
+                chunks
+                    .par_iter()
+                    .map(|chunk| chunk.parse(shards, &self.public_suffixes)),
+            );
+            // stable, the chunks of a list stay in order
+            parsed.sort_by_key(|p| p.list);
+
+            // line numbers restart with each list
+            let mut public_suffixes = Vec::new();
+            let mut first_line = 0;
+            for (i, p) in parsed.iter().enumerate() {
+                if i > 0 && parsed[i - 1].list != p.list {
+                    first_line = 0;
+                }
+                for (name, source) in &p.public_suffixes {
+                    warn!(
+                        "Refusing to block the public suffix {} from {}",
+                        name, sources[*source]
+                    );
+                }
+                public_suffixes.extend_from_slice(&p.public_suffixes);
+                rejected.extend(p.rejected.iter().map(|r| Rejection {
+                    line_number: first_line + r.line_number,
+                    ..r.clone()
+                }));
+                first_line += p.lines;
+            }
+            debug!("parsing: {}", start.elapsed().as_millis());
+
+            let cnames = resolver
+                .join()
+                .map_err(|_| io::Error::other("the DNS resolver thread panicked"))
+                .and_then(|r| r)
+                .map_err(Error::Resolver)?;
+            Ok::<_, Error>((parsed, public_suffixes, cnames))
+        })?;
+
+        let (whitelist, typed_whitelist) = self.whitelist(&cnames, &mut rejected);
+
+        for rejection in &rejected {
+            warn!("Rejected {}", rejection);
+        }
+
+        let start_baddies = start.elapsed().as_millis();
+        let fingerprints = match state {
+            Some(_) => state::fingerprints(shards, settings, &parsed, &whitelist, &typed_whitelist),
+            None => Vec::new(),
+        };
+        let (indexes, shard_statistics): (Vec<Index>, Vec<Statistics>) = (0..shards)
+            .into_par_iter()
+            .map(|i| {
+                let in_shard = |d: &str| shard(d, shards) == i;
+                let Some(state) = &state else {
+                    return process_baddies(
+                        parsed.iter().map(|p| p.domains[i].as_slice()),
+                        &whitelist,
+                        &typed_whitelist,
+                        &self.public_suffixes,
+                        in_shard,
+                    );
+                };
+                let input: Vec<&Domain> = parsed.iter().flat_map(|p| &p.domains[i]).collect();
+                match state.load_shard(i, fingerprints[i], &input) {
+                    Ok(Some((mut index, statistics))) => {
+                        debug!("Shard {} didn't change", i);
+                        index
+                            .typed_allowed
+                            .extend(shard_typed_whitelist(&typed_whitelist, in_shard));
+                        return (index, statistics);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Folding shard {} again: {}", i, e),
+                }
+                let (index, statistics) = process_baddies(
+                    parsed.iter().map(|p| p.domains[i].as_slice()),
+                    &whitelist,
+                    &typed_whitelist,
+                    &self.public_suffixes,
+                    in_shard,
+                );
+                if let Err(e) = state.save_shard(i, fingerprints[i], &input, &index, &statistics) {
+                    warn!("Not keeping the state of shard {}: {}", i, e);
+                }
+                (index, statistics)
+            })
+            .unzip();
+        debug!(
+            "processing baddies in {} shards: {}",
+            shards,
+            start.elapsed().as_millis() - start_baddies
+        );
+        if let Some(state) = &state {
+            self.save_state(
+                state,
+                shards,
+                settings,
+                &hashes,
+                &parsed,
+                &reparsed,
+                &first_sources,
+                &sources,
+                fingerprints,
+            );
+        }
+        let mut statistics = Statistics::default();
+        for (i, shard_statistics) in shard_statistics.iter().enumerate() {
+            debug!("Statistics shard {} \n{}", i, shard_statistics);
+            statistics.merge(shard_statistics);
+        }
+        statistics.add_public_suffixes(public_suffixes.len());
+        statistics.add_rejected(rejected.len());
+        log_statistics(&statistics);
+
+        Ok(BlockList {
+            indexes,
+            public_suffixes,
+            rejected,
+            sources,
+            statistics,
+            cnames,
+            order: Order::default(),
+        })
+    }
+
+    /// Keeps the lists parsed again by this build, then the manifest pointing to
+    /// everything. The state only saves work, failing to keep it is not an error,
+    /// the files are checked against the manifest when read
+    #[allow(clippy::too_many_arguments)]
+    fn save_state(
+        &self,
+        state: &State,
+        shards: usize,
+        settings: u64,
+        hashes: &[u64],
+        parsed: &[Parsed],
+        reparsed: &[bool],
+        first_sources: &[usize],
+        sources: &[String],
+        fingerprints: Vec<u64>,
+    ) {
+        let mut lists = Vec::with_capacity(self.block_sources.len());
+        for (list, source) in self.block_sources.iter().enumerate() {
+            if reparsed[list] {
+                let chunks: Vec<&Parsed> = parsed.iter().filter(|p| p.list == list).collect();
+                let first_source = first_sources[list];
+                let urls = &sources[first_source + 1..first_sources[list + 1]];
+                let saved = state.save_list(
+                    list,
+                    &source.text,
+                    hashes[list],
+                    urls,
+                    first_source,
+                    &chunks,
+                );
+                if let Err(e) = saved {
+                    warn!("Not keeping the state of {}: {}", source.name, e);
+                }
+            }
+            lists.push((source.name.clone(), hashes[list]));
+        }
+        if let Err(e) = state.save(shards, settings, lists, fingerprints) {
+            warn!("Not keeping the build state: {}", e);
+        }
+    }
+
+    /// The whitelisted domains with their parents and the CNAMEs, and the entries that
+    /// allow only some record types. The lines that are not valid names are rejected
+    pub(crate) fn whitelist<'s: 'c, 'c>(
+        &'s self,
+        cnames: &'c [String],
+        rejected: &mut Vec<Rejection<'s>>,
+    ) -> (HashSet<&'c str>, HashMap<&'s str, (QTypes, usize)>) {
+        let mut whitelist: HashSet<&str> = HashSet::default();
+        let mut typed_whitelist: HashMap<&str, (QTypes, usize)> = HashMap::default();
+
+        for (source, whitelist_source) in self.whitelist_sources.iter().enumerate() {
+            for (n, line) in whitelist_source.text.lines().enumerate() {
+                if let Some(domain) = parse_line(&whitelist_source.name, n + 1, line, rejected) {
+                    process_whitelist_domain(domain, source, &mut whitelist, &mut typed_whitelist);
+                }
+            }
+        }
+
+        for domain in cnames.iter().filter_map(|cname| Domain::new(cname)) {
+            whitelist_domain(domain.name, &mut whitelist);
+        }
+        (whitelist, typed_whitelist)
+    }
+
+    /// Names of the whitelists, the first sources of a block list
+    pub(crate) fn whitelist_names(&self) -> impl Iterator<Item = &str> {
+        self.whitelist_sources.iter().map(|s| s.name.as_str())
+    }
+
+    pub(crate) fn public_suffix_list(&self) -> &PublicSuffixList {
+        &self.public_suffixes
+    }
+
+    // expand the whitelisted domains with their cnames
+    pub(crate) fn expand_whitelist(&self) -> io::Result<Vec<String>> {
+        let mut cnames = Vec::with_capacity(50);
+        if !self.resolve_cnames {
+            return Ok(cnames);
+        }
+        let mut explicit_whitelisted_domains = Vec::with_capacity(50);
+        for source in &self.whitelist_sources {
+            for line in source.text.lines() {
+                if let Some(domain) =
+                    Domain::new(line).filter(|d| d.qtypes.is_none() && validate(d.name).is_ok())
+                {
+                    explicit_whitelisted_domains.push(domain.name);
+                }
+            }
+        }
+        dns_resolver::resolve_domain(
+            self.resolver_server
+                .as_deref()
+                .unwrap_or(dns_resolver::DEFAULT_SERVER),
+            self.resolver_timeout
+                .unwrap_or(dns_resolver::DEFAULT_TIMEOUT),
+            &explicit_whitelisted_domains,
+            &mut cnames,
+        )?;
+        debug!("Cnames to be whitelisted: {:#?}", cnames);
+        Ok(cnames)
+    }
+}
+
+/// An immutable index of blocked domains
+pub struct BlockList<'a> {
+    /// the shards, a domain is only ever looked up in its own one
+    indexes: Vec<Index<'a>>,
+    /// entries refused for being public suffixes, with their source
+    public_suffixes: Vec<(&'a str, usize)>,
+    rejected: Vec<Rejection<'a>>,
+    sources: Vec<String>,
+    statistics: Statistics<'a>,
+    /// the CNAMEs of the whitelisted domains, whitelisted with them
+    cnames: Vec<String>,
+    order: Order,
+}
+
+impl<'a> BlockList<'a> {
+    fn index(&self, domain: &str) -> &Index<'a> {
+        &self.indexes[shard(domain, self.indexes.len())]
+    }
+
+    /// Checks if a query for the domain is blocked, regardless of its record type
+    pub fn is_blocked(&self, domain: &str) -> bool {
+        self.check(domain, None).0 == QueryResult::Blocked
+    }
+
+    /// Decides what happens to a query for the domain and record type, returns the entry
+    /// that blocked, would have blocked or allowed it and the name of the list it came from
+    pub fn check(
+        &self,
+        domain: &str,
+        qtype: Option<&str>,
+    ) -> (QueryResult, Option<(&'a str, &str)>) {
+        let domain = normalize_name(domain);
+        let index = self.index(&domain);
+        let (result, found) =
+            if let Some(found) = qtype.and_then(|t| index.find_allowed_type(&domain, t)) {
+                (QueryResult::Whitelisted, Some(found))
+            } else if let Some(found) = index.find_blocking(&domain, qtype) {
+                (QueryResult::Blocked, Some(found))
+            } else if let Some(found) = index.find_whitelisting(&domain) {
+                (QueryResult::Whitelisted, Some(found))
+            } else {
+                (QueryResult::Allowed, None)
+            };
+        (
+            result,
+            found.map(|(entry, source)| (entry, self.sources[source].as_str())),
+        )
+    }
+
+    /// Sets the order of the domains in the outputs, sorted outputs are the same
+    /// for the same input, unsorted ones are faster to write
+    pub fn with_order(mut self, order: Order) -> BlockList<'a> {
+        self.order = order;
+        self
+    }
+
+    /// Writes the blocked domains in the given format
+    pub fn write(&self, format: Format, w: &mut impl Write) -> io::Result<()> {
+        self.write_in_order(format, self.order, w)
+    }
+
+    /// Writes the blocked domains in the given format and order
+    pub(crate) fn write_in_order(
+        &self,
+        format: Format,
+        order: Order,
+        w: &mut impl Write,
+    ) -> io::Result<()> {
+        let indexes: Vec<&Index> = self.indexes.iter().collect();
+        writers::write(format, order, &indexes, &self.sources, w)
+    }
+
+    /// Replaces a file with the blocked domains in the given format, atomically,
+    /// keeping the previous `keep` versions as file.1 to file.keep
+    pub fn write_file(&self, format: Format, path: &str, keep: usize) -> Result<()> {
+        self.write_compressed_file(format, Compression::None, path, keep)
+    }
+
+    /// Replaces a file with the blocked domains in the given format and compression
+    pub fn write_compressed_file(
+        &self,
+        format: Format,
+        compression: Compression,
+        path: &str,
+        keep: usize,
+    ) -> Result<()> {
+        output::write_atomic(path, keep, |f| {
+            compression::compress(compression, f, |w| self.write(format, w))
+        })
+    }
+
+    /// Writes several files from the same index in parallel, each output is given
+    /// as its format, compression, path and number of previous versions to keep
+    pub fn write_files(&self, outputs: &[(Format, Compression, &str, usize)]) -> Result<()> {
+        outputs
+            .par_iter()
+            .try_for_each(|(format, compression, path, keep)| {
+                self.write_compressed_file(*format, *compression, path, *keep)?;
+                info!("Wrote {} in {} format", path, format);
+                Ok(())
+            })
+    }
+
+    /// Number of blocked entries, not counting the subdomains they cover
+    pub fn len(&self) -> usize {
+        self.indexes.iter().map(|index| index.blocked.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All the blocked entries, including the ones for some record types only
+    pub fn entries(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.indexes.iter().flat_map(|index| {
+            index
+                .blocked
+                .iter()
+                .map(|(d, _)| d)
+                .chain(index.typed_blocked.keys().copied())
+        })
+    }
+
+    /// Entries that were not blocked because they are public suffixes, with the
+    /// name of the list they came from
+    pub fn public_suffixes(&self) -> impl ExactSizeIterator<Item = (&'a str, &str)> + '_ {
+        self.public_suffixes
+            .iter()
+            .map(|(entry, source)| (*entry, self.sources[*source].as_str()))
+    }
+
+    /// Input lines that were left out for not being valid domain names
+    pub fn rejected(&self) -> &[Rejection<'a>] {
+        &self.rejected
+    }
+
+    /// Writes the rejected input lines to a file, one per line with the
+    /// file:line they came from and the reason
+    pub fn write_rejects(&self, path: &str) -> Result<()> {
+        output::write_atomic(path, 0, |f| {
+            for rejection in &self.rejected {
+                writeln!(f, "{}", rejection)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Names of the lists the entries came from, whitelists first
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    /// What happened to the domains read from the block lists
+    pub fn statistics(&self) -> &Statistics<'a> {
+        &self.statistics
+    }
+}
+
+/// Adds a domain from a whitelist to the whitelist index
+/// It adds the domain and all parent domains
+/// Entries for some record types only go to the typed index, they don't keep
+/// the other types from being blocked
+fn process_whitelist_domain<'a, 'b>(
+    domain: Domain<'a>,
+    source: usize,
+    index: &mut HashSet<&'b str>,
+    typed_index: &mut HashMap<&'a str, (QTypes, usize)>,
+) where
+    'a: 'b,
+{
+    if let Some(qtypes) = domain.qtypes {
+        let types = typed_index
+            .get(domain.name)
+            .map(|(t, _)| t.union(qtypes))
+            .unwrap_or(qtypes);
+        typed_index.insert(domain.name, (types, source));
+        return;
+    }
+    whitelist_domain(domain.name, index);
+}
+
+fn whitelist_domain<'a>(domain: &'a str, index: &mut HashSet<&'a str>) {
+    for seg in sub_domain_iterator(domain, 1) {
+        index.insert(seg);
+    }
+    index.insert(domain);
+}
+
+/// adds a domain to the blocked index if it's not already blocked or whitelisted, the
+/// domains come sorted, last is the last name blocked for all record types, the only one
+/// that can cover a name blocked for all of them
+/// covered tells if a name blocked for all types, this one or a parent, came in before it,
+/// returns what happened to a domain blocked for all types
+fn process_bad_domain<'a>(
+    domain: &Domain<'a>,
+    last: Option<&'a str>,
+    covered: impl FnOnce() -> bool,
+    index: &mut Index<'a>,
+    whitelist: &HashSet<&str>,
+    public_suffixes: &PublicSuffixList,
+    statistics: &mut Statistics<'a>,
+) -> Option<Insert<'a>> {
+    let Domain {
+        name: domain,
+        source,
+        qtypes,
+        ..
+    } = *domain;
+    if whitelist.contains(domain) {
+        if index.whitelisted.insert(domain, source).is_none() {
+            statistics.increment_distinct_whitelisted();
+        }
+        debug!("Whitelisted {}", domain);
+        statistics.increment_whitelisted();
+        return None;
+    }
+    let registrable = |name: &'a str| public_suffixes.registrable_domain(name).unwrap_or(name);
+    match qtypes {
+        Some(qtypes) => {
+            if covered() {
+                statistics.increment_parent(registrable(domain));
+                return None;
+            }
+            match index.typed_blocked.get_mut(domain) {
+                Some((types, _)) => {
+                    *types = types.union(qtypes);
+                    statistics.increment_duplicate();
+                }
+                None => {
+                    index.typed_blocked.insert(domain, (qtypes, source));
+                    statistics.increment_blocked();
+                }
+            }
+            None
+        }
+        None => {
+            let insert = Insert::after(last, domain);
+            match insert {
+                Insert::Added => statistics.increment_blocked(),
+                Insert::Duplicate => statistics.increment_duplicate(),
+                Insert::Covered(_) => statistics.increment_parent(registrable(domain)),
+            }
+            Some(insert)
+        }
+    }
+}
+
+/// The first bytes of the labels from the top level domain down, separated by a zero
+/// byte, names sort by it as by [`cmp_labels`] unless they start the same
+fn label_key(name: &str) -> (u64, u64) {
+    let mut key = [0u8; 16];
+    let bytes = labels(name)
+        .enumerate()
+        .flat_map(|(i, label)| (i > 0).then_some(0).into_iter().chain(label.bytes()));
+    for (k, b) in key.iter_mut().zip(bytes) {
+        *k = b;
+    }
+    let (high, low) = key.split_at(8);
+    (
+        u64::from_be_bytes(high.try_into().unwrap()),
+        u64::from_be_bytes(low.try_into().unwrap()),
+    )
+}
+
+/// Logs the statistics of a build and the registrable domains with most subdomains folded
+pub(crate) fn log_statistics(statistics: &Statistics) {
+    info!("Statistics total \n{}", statistics);
+    let top_folded = statistics.top_folded(10);
+    if !top_folded.is_empty() {
+        info!(
+            "Most subdomains folded by registrable domain\n{}",
+            top_folded
+                .iter()
+                .map(|(domain, count)| format!("{:<50} {:>7}\n", domain, count))
+                .collect::<String>()
+        );
+    }
+}
+
+/// Makes the index of one shard from its domains to block, the first of the same names
+/// in the order of the parsed chunks counts, in_shard selects
+/// the typed whitelist entries that belong to it
+#[doc(hidden)] // public for the benchmarks
+pub fn process_baddies<'a, 'd>(
+    bad_domains: impl Iterator<Item = &'d [Domain<'a>]>,
+    whitelist: &HashSet<&str>,
+    typed_whitelist: &HashMap<&'a str, (QTypes, usize)>,
+    public_suffixes: &PublicSuffixList,
+    in_shard: impl Fn(&str) -> bool,
+) -> (Index<'a>, Statistics<'a>)
+where
+    'a: 'd,
+{
+    let chunks: Vec<&[Domain]> = bad_domains.collect();
+    let starts: Vec<usize> = chunks
+        .iter()
+        .scan(0, |start, chunk| {
+            *start += chunk.len();
+            Some(*start - chunk.len())
+        })
+        .collect();
+    let domain_at = |position: usize| {
+        let chunk = starts.partition_point(|start| *start <= position) - 1;
+        &chunks[chunk][position - starts[chunk]]
+    };
+    // a parent right before its subdomains, the same names blocked for all types
+    // before the typed ones, otherwise in the order they came in
+    let mut sorted: Vec<((u64, u64), usize)> =
+        Vec::with_capacity(chunks.iter().map(|chunk| chunk.len()).sum());
+    sorted.extend(
+        chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .enumerate()
+            .filter(|(_, d)| !d.name.is_empty())
+            .map(|(position, d)| (label_key(d.name), position)),
+    );
+    sorted.sort_unstable_by(|(key_a, a), (key_b, b)| {
+        key_a.cmp(key_b).then_with(|| {
+            let (x, y) = (domain_at(*a), domain_at(*b));
+            cmp_labels(x.name, y.name)
+                .then(x.qtypes.is_some().cmp(&y.qtypes.is_some()))
+                .then(a.cmp(b))
+        })
+    });
+    // the entries blocked for all types take the place of the names as they are kept
+    let mut entries: Vec<(&'a str, usize)> = sorted
+        .into_iter()
+        .map(|(_, position)| (domain_at(position).name, position))
+        .collect();
+
+    let mut index = Index::new(DomainSet::default());
+    let mut statistics = Statistics::default();
+    let mut kept: usize = 0;
+    // where the names blocked for all types above the current one came in
+    let mut path: Vec<usize> = Vec::new();
+    for i in 0..entries.len() {
+        let (name, position) = entries[i];
+        while path.last().is_some_and(|parent| {
+            let mut name_labels = labels(name);
+            !labels(domain_at(*parent).name).all(|label| name_labels.next() == Some(label))
+        }) {
+            path.pop();
+        }
+        let domain = domain_at(position);
+        let last = kept.checked_sub(1).map(|k| entries[k].0);
+        let covered = || path.iter().any(|parent| *parent < position);
+        if let Some(insert) = process_bad_domain(
+            domain,
+            last,
+            covered,
+            &mut index,
+            whitelist,
+            public_suffixes,
+            &mut statistics,
+        ) {
+            if insert == Insert::Added {
+                entries[kept] = (name, domain.source);
+                kept += 1;
+            }
+            path.push(position);
+        }
+    }
+    entries.truncate(kept);
+    index.blocked = DomainSet::from_sorted(entries);
+    // a name or parent blocked for all types might have come after the typed entry
+    let blocked = &index.blocked;
+    index.typed_blocked.retain(|d, _| blocked.find(d).is_none());
+    index
+        .typed_allowed
+        .extend(shard_typed_whitelist(typed_whitelist, in_shard));
+    (index, statistics)
+}
+
+/// The typed whitelist entries of a shard, a top level domain covers names in all the shards
+pub(crate) fn shard_typed_whitelist<'a, 'w>(
+    typed_whitelist: &'w HashMap<&'a str, (QTypes, usize)>,
+    in_shard: impl Fn(&str) -> bool + 'w,
+) -> impl Iterator<Item = (&'a str, (QTypes, usize))> + 'w {
+    typed_whitelist
+        .iter()
+        .filter(move |(d, _)| in_shard(d) || !d.trim_end_matches('.').contains('.'))
+        .map(|(d, t)| (*d, *t))
+}
+
+impl Lookup for BlockList<'_> {
+    fn check(&self, domain: &str, qtype: Option<&str>) -> (QueryResult, Option<(&str, &str)>) {
+        BlockList::check(self, domain, qtype)
+    }
+
+    fn names(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.indexes.iter().flat_map(|index| {
+            index
+                .blocked
+                .iter()
+                .map(|(d, _)| d)
+                .chain(index.whitelisted.keys().copied())
+                .chain(index.typed_blocked.keys().copied())
+                .chain(index.typed_allowed.keys().copied())
+        }))
+    }
+
+    fn has_typed_entry(&self, domain: &str) -> bool {
+        let domain = normalize_name(domain);
+        let index = self.index(&domain);
+        index.typed_blocked.contains_key(&*domain)
+            || std::iter::once(&*domain)
+                .chain(sub_domain_iterator(&domain, 1))
+                .any(|seg| index.typed_allowed.contains_key(seg))
+    }
+}
+
+#[cfg(test)]
+mod tests_blocklist {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn build_test() {
+        let builder = BlockListBuilder::new()
+            .block_source("personal", "Tracker.com\nBücher.de\n".to_string())
+            .block_source(
+                "public",
+                "0.0.0.0 ads.fb.com\n# dns-block: https://example.org/list\nx.ads.fb.com\ngood.fb.com\nco.uk\n0.0.0.0 http://ads.x.com/a\nx.com $type=BOGUS\n"
+                    .to_string(),
+            )
+            .whitelist_source("whitelist", "www.good.fb.com\n".to_string());
+        let blocklist = builder.build().unwrap();
+
+        assert!(blocklist.is_blocked("x.ads.fb.com"));
+        assert!(blocklist.is_blocked("WWW.tracker.com"));
+        assert!(!blocklist.is_blocked("good.fb.com"));
+        assert_eq!(
+            (QueryResult::Blocked, Some(("tracker.com", "personal"))),
+            blocklist.check("tracker.com", Some("A"))
+        );
+        assert_eq!(
+            (
+                QueryResult::Whitelisted,
+                Some(("good.fb.com", "https://example.org/list"))
+            ),
+            blocklist.check("good.fb.com", Some("A"))
+        );
+        assert!(blocklist.is_blocked("xn--bcher-kva.de"));
+        assert!(blocklist.is_blocked("www.BÜCHER.de"));
+        assert_eq!(3, blocklist.len());
+        assert!(!blocklist.is_blocked("bbc.co.uk"));
+        assert_eq!(
+            vec![("co.uk", "https://example.org/list")],
+            blocklist.public_suffixes().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![("fb.com", 1)], blocklist.statistics().top_folded(10));
+        assert_eq!(
+            vec![
+                "public:6: a URL, not a domain name: 0.0.0.0 http://ads.x.com/a",
+                "public:7: unknown record type: x.com $type=bogus"
+            ],
+            blocklist
+                .rejected()
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        let mut out = Vec::new();
+        blocklist.write(Format::Plain, &mut out).unwrap();
+        let mut lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort_unstable();
+        assert_eq!(vec!["ads.fb.com", "tracker.com", "xn--bcher-kva.de"], lines);
+
+        let mut out = Vec::new();
+        blocklist.write(Format::PlainUnicode, &mut out).unwrap();
+        assert!(std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .any(|l| l == "xn--bcher-kva.de # bücher.de"));
+    }
+
+    #[test]
+    fn trailing_dot_test() {
+        let builder = BlockListBuilder::new()
+            .block_source(
+                "list",
+                "tracker.net.\nfb.org. $type=HTTPS\nads.fb.com.\n".to_string(),
+            )
+            .whitelist_source("white", "www.ads.fb.com.\n".to_string());
+        let blocklist = builder.build().unwrap();
+        let mut out = Vec::new();
+        blocklist.write(Format::Bind, &mut out).unwrap();
+        let bind = String::from_utf8(out).unwrap();
+        assert!(bind.contains("\ntracker.net CNAME .\n*.tracker.net CNAME .\n"));
+        assert!(!bind.contains("tracker.net."));
+        assert_eq!(
+            (QueryResult::Blocked, Some(("fb.org", "list"))),
+            blocklist.check("fb.org.", Some("HTTPS"))
+        );
+        assert_eq!(
+            (QueryResult::Whitelisted, Some(("ads.fb.com", "list"))),
+            blocklist.check("ads.fb.com.", None)
+        );
+    }
+
+    #[test]
+    fn pieces_test() {
+        // a converted piece is longer, what comes after it moves
+        let mut list = "bücher.de\n".to_string();
+        while list.len() < 2 * CHUNK_SIZE {
+            list.push_str(&format!("d{}.com\n", list.len()));
+        }
+        let lines = list.lines().count();
+        list.push_str(
+            "# dns-block: https://example.org/list\nBÜCHER.example\n0.0.0.0 http://ads.x.com/a\n",
+        );
+        let builder = BlockListBuilder::new().block_source("list", list);
+        let blocklist = builder.build().unwrap();
+        assert_eq!(
+            (
+                QueryResult::Blocked,
+                Some(("xn--bcher-kva.example", "https://example.org/list"))
+            ),
+            blocklist.check("xn--bcher-kva.example", None)
+        );
+        assert!(blocklist.is_blocked("xn--bcher-kva.de"));
+        assert_eq!(lines + 3, blocklist.rejected()[0].line_number);
+    }
+
+    #[test]
+    fn from_scratch_test() {
+        let builder = || {
+            BlockListBuilder::new()
+                .block_source("list", "ads.fb.com\ntracker.net\n".to_string())
+                .whitelist_source("white", "good.example\n".to_string())
+        };
+        let first_builder = builder();
+        let mut first = first_builder.build().unwrap();
+        first.cnames = vec!["cdn.ads.fb.com".to_string()];
+        // nothing answers there, the CNAMEs must come from the first build
+        let builder = builder()
+            .resolve_cnames(true)
+            .resolver_server("192.0.2.1:53")
+            .resolver_timeout(Duration::from_millis(1));
+        let full = builder.build_from_scratch(&first).unwrap();
+        assert_eq!(
+            QueryResult::Whitelisted,
+            full.check("cdn.ads.fb.com", None).0
+        );
+        assert!(full.is_blocked("tracker.net"));
+    }
+
+    #[test]
+    fn write_files_test() {
+        let builder = BlockListBuilder::new().block_source("list", "ads.fb.com\n".to_string());
+        let blocklist = builder.build().unwrap();
+        let dir = std::env::temp_dir().join(format!("dns-block-outputs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let plain = dir.join("domains.blocked");
+        let unbound = dir.join("unbound.conf");
+        blocklist
+            .write_files(&[
+                (Format::Plain, Compression::None, plain.to_str().unwrap(), 0),
+                (
+                    Format::Unbound,
+                    Compression::Gzip,
+                    unbound.to_str().unwrap(),
+                    0,
+                ),
+            ])
+            .unwrap();
+        assert_eq!("ads.fb.com\n", fs::read_to_string(&plain).unwrap());
+        assert_eq!(
+            "local-zone: \"ads.fb.com.\" always_nxdomain\n",
+            compression::read_to_string(unbound.to_str().unwrap()).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shards_test() {
+        let list =
+            "x.ads.fb.com\nads.fb.com\nfoocom\nads.foocom\ntracker.net\nfb.org $type=HTTPS\n";
+        let sorted_entries = |shards| {
+            let builder = BlockListBuilder::new()
+                .block_source("list", list.to_string())
+                .shards(shards);
+            let blocklist = builder.build().unwrap();
+            assert!(blocklist.is_blocked("x.ads.fb.com"));
+            assert!(blocklist.is_blocked("ads.foocom"));
+            let mut entries: Vec<&str> = blocklist.entries().collect();
+            entries.sort_unstable();
+            (entries.join(","), blocklist.statistics().to_string())
+        };
+        assert_eq!(sorted_entries(1), sorted_entries(5));
+        assert_eq!(
+            "ads.fb.com,ads.foocom,fb.org,tracker.net",
+            sorted_entries(3).0
+        );
+    }
+
+    #[test]
+    fn chunks_test() {
+        // several chunks, with the line numbers and sources carried across them
+        let mut list = String::from("# dns-block: https://example.org/first\n");
+        for i in 1..40_000 {
+            match i {
+                20_000 => list.push_str("# dns-block: https://example.org/second\n"),
+                39_999 => list.push_str("bad_name.example.com\n"),
+                _ => list.push_str(&format!("0.0.0.0 d{}.example.com\n", i)),
+            }
+        }
+        assert!(list.len() > 2 * CHUNK_SIZE);
+        let builder = BlockListBuilder::new().block_source("big", list).shards(3);
+        let blocklist = builder.build().unwrap();
+        assert_eq!(39_997, blocklist.len());
+        assert_eq!(
+            (
+                QueryResult::Blocked,
+                Some(("d1.example.com", "https://example.org/first"))
+            ),
+            blocklist.check("d1.example.com", None)
+        );
+        assert_eq!(
+            Some(("d20001.example.com", "https://example.org/second")),
+            blocklist.check("d20001.example.com", None).1
+        );
+        let rejected: Vec<String> = blocklist.rejected().iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            vec!["big:40000: underscore inside a label: bad_name.example.com"],
+            rejected
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use clap::{Parser, Subcommand};
+use dns_block::filter::OutputFormat;
+use dns_block::low_memory::MIN_MEMORY_LIMIT;
+use dns_block::{inputs, Compression, Format, Order};
 
 #[derive(Parser, Debug)]
 #[command(name = "dns-block", author, version, about, long_about)]
@@ -11,16 +14,80 @@ pub struct Cli {
     #[arg(short, long)]
     pub timing: bool,
 
-    /// File containing the list of domains to dns block.
-    #[arg(name = "domains.blocked", value_parser = file_exists)]
-    pub domain_block_filename: String,
+    /// Configuration file listing the block lists, whitelists and outputs,
+    /// used instead of the file arguments
+    #[arg(short, long, value_parser = file_exists,
+          conflicts_with_all = ["domains.blocked", "domains.whitelist", "hosts_blocked.txt",
+                                "block", "whitelist", "personal"])]
+    pub config: Option<String>,
 
-    /// File containing the list of domains to whitelist. Use - to skip this parameter
-    #[arg(name = "domains.whitelist", value_parser = file_exists)]
+    /// Answer the pipe and audit queries from an index written by pack -o packed:FILE
+    /// instead of reading the lists
+    #[arg(short, long, value_parser = file_exists,
+          conflicts_with_all = ["config", "domains.blocked", "domains.whitelist", "hosts_blocked.txt",
+                                "block", "whitelist", "personal"])]
+    pub index: Option<String>,
+
+    /// Copy of the Public Suffix List (public_suffix_list.dat), entries that are public
+    /// suffixes are not blocked. Without it only the most common suffixes are known
+    #[arg(long, value_parser = file_exists)]
+    pub public_suffix_list: Option<String>,
+
+    /// Write the input lines that are not valid domain names to this file,
+    /// with the file:line they came from and the reason
+    #[arg(long)]
+    pub rejects: Option<String>,
+
+    /// Order of the domains in the outputs: unsorted, the fastest, alphabetical or
+    /// reversed, by the labels from the top level domain down so related domains sit together
+    #[arg(long)]
+    pub sort: Option<Order>,
+
+    /// Number of parts the domains are split in and folded in parallel,
+    /// the number of cores by default
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub shards: Option<u16>,
+
+    /// Keep what the build parsed in this directory, the next run only parses the lists
+    /// that changed and folds again the parts of the index they touch
+    #[arg(long)]
+    pub state_dir: Option<String>,
+
+    /// Build from scratch as well and fail if the result differs from the incremental build
+    #[arg(long)]
+    pub verify_state: bool,
+
+    /// Megabytes the build may use, the lists are sorted and folded on disk instead of
+    /// in memory. The outputs are the same, unsorted ones come out reversed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(MIN_MEMORY_LIMIT..),
+          conflicts_with_all = ["index", "state_dir", "verify_state"])]
+    pub memory_limit: Option<u64>,
+
+    /// More lists of domains to block, as files, directories, globs like 'lists.d/*.list'
+    /// or - for stdin. Can be repeated
+    #[arg(short, long, value_name = "LIST", value_parser = list_path)]
+    pub block: Vec<String>,
+
+    /// More lists of domains to whitelist, as for --block. Can be repeated
+    #[arg(short, long, value_name = "LIST", value_parser = list_path)]
+    pub whitelist: Vec<String>,
+
+    /// More personal lists of domains to block, their entries are credited to them.
+    /// Can be repeated
+    #[arg(short, long, value_name = "LIST", value_parser = list_path)]
+    pub personal: Vec<String>,
+
+    /// File, directory or glob with the domains to dns block, - for stdin.
+    /// Not needed for rollback or with --block
+    #[arg(name = "domains.blocked", value_parser = list_path)]
+    pub domain_block_filename: Option<String>,
+
+    /// File, directory or glob with the domains to whitelist. Use - to skip this parameter
+    #[arg(name = "domains.whitelist", value_parser = list_path, default_value = "-")]
     pub domain_whitelist_filename: String,
 
     /// Additional personal file with domains to block. Use - to skip this parameter
-    #[arg(name = "hosts_blocked.txt", value_parser = file_exists)]
+    #[arg(name = "hosts_blocked.txt", value_parser = list_path, default_value = "-")]
     pub hosts_blocked_filename: String,
 
     #[command(subcommand)]
@@ -29,20 +96,98 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
+    /// Write all the outputs listed in the configuration file
+    Build,
+    /// Restore the previous version of an output file written by pack
+    Rollback {
+        /// Output file to restore
+        #[arg(name = "output_file", default_value = "simple.blocked")]
+        output_file: String,
+    },
     /// Pack the domains list into one file
     Pack {
         /// output in Bind9 format
+        #[arg(short, long, conflicts_with = "unbound")]
+        bind: bool,
+        /// output in unbound local-zone format
         #[arg(short, long)]
-        bind:        bool,
+        unbound: bool,
+        /// add the Unicode form of internationalized domains as a comment in the plain output
+        #[arg(long, conflicts_with_all = ["bind", "unbound"])]
+        unicode: bool,
         /// Output file
         #[arg(name = "output_file", default_value = "simple.blocked")]
         output_file: String,
+        /// Write this output instead, as format:path with format one of plain, plain-unicode,
+        /// bind, unbound or packed. Can be repeated, all of them are written in parallel
+        #[arg(short, long, value_parser = output_spec,
+              conflicts_with_all = ["bind", "unbound", "unicode", "output_file"])]
+        output: Vec<(Format, String)>,
+        /// Number of previous versions of the output file to keep for rollback, older ones are
+        /// removed. 0 keeps no new version and leaves the existing ones alone
+        #[arg(short, long, default_value_t = 3)]
+        keep: usize,
+        /// Compress the outputs with gzip, zstd or xz, the file names are kept as given.
+        /// Not for packed outputs, --index maps them as they are
+        #[arg(long, default_value_t = Compression::None)]
+        compress: Compression,
+        /// Refuse to write if the number of entries changes by more than this percentage
+        /// compared to the previous output file, 0 (the default) to disable the check
+        #[arg(long, default_value_t = 0.0)]
+        max_change: f64,
+        /// File with domains that must keep resolving, writing fails if any would be blocked
+        #[arg(long, value_parser = file_exists)]
+        protect: Option<String>,
+        /// Write the output even if it fails the sanity checks
+        #[arg(long)]
+        force: bool,
     },
     /// Act as a pipe when tailing the Bind9 query log
     Pipe {
-        /// Filter for just these client IPs (comma separated list)
+        /// Filter for just these clients (comma separated list of IPs, CIDR networks
+        /// and @group names, prefix an entry with ! to exclude it, e.g. @kids,!10.0.5.1)
         #[arg(short, long)]
         filter: Option<String>,
+        /// File with named client groups, one per line: kids = 10.0.5.0/24, 2001:db8:5::/64
+        #[arg(short, long, value_parser = file_exists)]
+        groups: Option<String>,
+        /// Output format, text or json, json writes one object per query
+        #[arg(short, long, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+        /// Instead of the queries, show a table of the top clients and domains,
+        /// refreshed every interval and on SIGUSR1, and a final one at the end of the input
+        #[arg(short, long)]
+        summary: bool,
+        /// Number of entries in each summary table
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        /// Seconds between summary refreshes
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+        /// Ask a Bloom filter with this many bits per name before the block list, most names
+        /// that are not blocked skip the lookups. A stats line with the lookups per second
+        /// and the false positive rate is logged with -dd on SIGUSR1 and at the end of the input
+        #[arg(long, value_name = "BITS", value_parser = clap::value_parser!(u8).range(1..=64))]
+        prefilter: Option<u8>,
+    },
+    /// Replay a saved Bind9 query log against the block list and report what it would block
+    Audit {
+        /// Query log to replay, - for stdin
+        #[arg(name = "query.log", value_parser = file_exists)]
+        log_file: String,
+        /// Block list currently in use, as written by pack in any of the formats, to
+        /// compare against. Without it every logged query is taken as allowed
+        #[arg(short, long, value_parser = file_exists)]
+        baseline: Option<String>,
+        /// Only replay queries of these clients, same syntax as for pipe
+        #[arg(short, long)]
+        filter: Option<String>,
+        /// File with named client groups
+        #[arg(short, long, value_parser = file_exists)]
+        groups: Option<String>,
+        /// Number of entries in each table
+        #[arg(long, default_value_t = 20)]
+        top: usize,
     },
 }
 
@@ -57,3 +202,20 @@ fn file_exists(path: &str) -> Result<String, String> {
         Err(format!("{path}: No such file or directory"))
     }
 }
+
+fn list_path(path: &str) -> Result<String, String> {
+    if inputs::is_pattern(path) {
+        glob::Pattern::new(path)
+            .map(|_| path.to_string())
+            .map_err(|e| format!("{path}: {e}"))
+    } else {
+        file_exists(path)
+    }
+}
+
+fn output_spec(spec: &str) -> Result<(Format, String), String> {
+    match spec.split_once(':') {
+        Some((format, path)) if !path.is_empty() => Ok((format.parse()?, path.to_string())),
+        _ => Err(format!("{spec}: expected format:path, e.g. bind:rpz.db")),
+    }
+}
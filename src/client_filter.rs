@@ -0,0 +1,201 @@
+use std::fs;
+use std::net::IpAddr;
+
+use crate::error::{self, Error};
+use fnv::FnvHashMap as HashMap;
+use log::*;
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. 10.0.5.0/24 or 2001:db8::/32
+/// A plain address is a network with the full prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn parse(s: &str) -> Result<IpNet, String> {
+        let (addr_str, prefix_str) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr_str
+            .parse()
+            .map_err(|_| format!("{s}: not an IP address or network"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix_str {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{s}: invalid prefix length"))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net) as u128, 32, self.prefix)
+                    == masked(u32::from(*ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(u128::from(net), 128, self.prefix)
+                    == masked(u128::from(*ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits >> (width - prefix)
+    }
+}
+
+/// Selects the clients whose queries are shown in pipe mode
+///
+/// The filter is a comma separated list of addresses, networks and @group names,
+/// each of them optionally negated with a leading !, e.g. `@kids,!10.0.5.1`
+/// A client matches if it is in one of the positive entries (or there are none)
+/// and in none of the negated ones.
+#[derive(Debug, Default)]
+pub struct ClientFilter {
+    include: Vec<IpNet>,
+    exclude: Vec<IpNet>,
+}
+
+impl ClientFilter {
+    /// Builds the filter from the --filter parameter and the optional groups file
+    pub fn new(spec: Option<&str>, groups_filename: Option<&str>) -> error::Result<ClientFilter> {
+        let groups = match groups_filename {
+            Some(f) => {
+                let content = fs::read_to_string(f).map_err(|e| Error::input(f, e))?;
+                parse_groups(&content).map_err(|e| Error::InvalidInput(format!("{f}: {e}")))?
+            }
+            None => HashMap::default(),
+        };
+
+        let mut client_filter = ClientFilter::default();
+        for item in spec.unwrap_or_default().split(',').map(str::trim) {
+            if item.is_empty() {
+                continue;
+            }
+            let (negated, item) = match item.strip_prefix('!') {
+                Some(rest) => (true, rest.trim()),
+                None => (false, item),
+            };
+            let nets = match item.strip_prefix('@') {
+                Some(name) => groups
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| Error::InvalidInput(format!("@{name}: unknown client group")))?,
+                None => vec![IpNet::parse(item).map_err(Error::InvalidInput)?],
+            };
+            if negated {
+                client_filter.exclude.extend(nets);
+            } else {
+                client_filter.include.extend(nets);
+            }
+        }
+        debug!("Client filter: {:#?}", client_filter);
+        Ok(client_filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Checks the client address as found in the log, an unparsable address
+    /// only passes an empty filter
+    pub fn matches(&self, client: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        // link local IPv6 addresses can carry a zone, e.g. fe80::1%eth0
+        let client = client.split('%').next().unwrap_or(client);
+        match client.parse::<IpAddr>() {
+            Ok(ip) => {
+                (self.include.is_empty() || self.include.iter().any(|n| n.contains(&ip)))
+                    && !self.exclude.iter().any(|n| n.contains(&ip))
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Parses a client groups file, one group per line:
+/// `kids = 10.0.5.0/24, 2001:db8:5::/64 # comment`
+fn parse_groups(content: &str) -> Result<HashMap<String, Vec<IpNet>>, String> {
+    let mut groups = HashMap::default();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(idx) => &line[0..idx],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, members) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `name = addresses`", line_number + 1))?;
+        let nets = members
+            .split(',')
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .map(IpNet::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("line {}: {e}", line_number + 1))?;
+        groups.insert(name.trim().to_string(), nets);
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests_client_filter {
+    use super::*;
+
+    #[test]
+    fn cidr_test() {
+        let net = IpNet::parse("10.0.5.0/24").unwrap();
+        assert!(net.contains(&"10.0.5.77".parse().unwrap()));
+        assert!(!net.contains(&"10.0.6.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+
+        let net = IpNet::parse("2001:db8::/32").unwrap();
+        assert!(net.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!(IpNet::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"1.2.3.4".parse().unwrap()));
+        assert!(IpNet::parse("10.0.0.0/33").is_err());
+        assert!(IpNet::parse("mydomain.com").is_err());
+    }
+
+    #[test]
+    fn filter_test() {
+        let groups = parse_groups("kids = 10.0.5.0/24, 2001:db8:5::/64 # the kids\n\n").unwrap();
+        let mut filter = ClientFilter::default();
+        filter.include.extend(groups["kids"].iter());
+        filter.exclude.push(IpNet::parse("10.0.5.1").unwrap());
+
+        assert!(filter.matches("10.0.5.30"));
+        assert!(filter.matches("2001:db8:5::30"));
+        assert!(!filter.matches("10.0.5.1"));
+        assert!(!filter.matches("10.0.0.30"));
+        assert!(!filter.matches("garbage"));
+
+        let negated = ClientFilter::new(Some("!10.0.0.30"), None).unwrap();
+        assert!(negated.matches("10.0.0.31"));
+        assert!(!negated.matches("10.0.0.30"));
+
+        assert!(ClientFilter::new(None, None).unwrap().matches("garbage"));
+        assert!(ClientFilter::new(Some("@unknown"), None).is_err());
+    }
+}
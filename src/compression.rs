@@ -0,0 +1,238 @@
+//! Reading lists and logs compressed with gzip, zstd or xz, and compressing the outputs
+//!
+//! Inputs are recognized by their first bytes whatever their name, so upstream lists
+//! can be kept as they were downloaded. Outputs are only compressed when asked to.
+
+use crate::error::{Error, Result};
+use crate::inputs;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Chain, Cursor, Read, Write};
+use std::str::FromStr;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0];
+/// Bytes to read to tell the formats apart, the longest magic
+const MAGIC_LEN: usize = XZ_MAGIC.len();
+/// An input with the bytes read to detect its compression put back in front
+pub type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+/// Size of the buffer in front of the encoder, the writers write a line at a time
+const BUFFER: usize = 64 * 1024;
+
+/// Compression of an input or an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// The compression of data starting with these bytes
+    pub fn detect(start: &[u8]) -> Compression {
+        if start.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if start.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    /// The compression of the input and the input to read from then, its first bytes
+    /// are read until they tell or the input ends, a pipe may give them a few at a time
+    pub fn of<R: BufRead>(mut input: R) -> io::Result<(Compression, Peeked<R>)> {
+        let mut start = Vec::with_capacity(MAGIC_LEN);
+        input
+            .by_ref()
+            .take(MAGIC_LEN as u64)
+            .read_to_end(&mut start)?;
+        Ok((Compression::detect(&start), Cursor::new(start).chain(input)))
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(format!(
+                "{s}: unknown compression, use none, gzip, zstd or xz"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        })
+    }
+}
+
+/// Decompresses the input if it starts like one of the known formats, concatenated
+/// compressed streams are read one after the other
+pub fn reader<'r>(input: impl BufRead + 'r) -> io::Result<Box<dyn BufRead + 'r>> {
+    let (compression, input) = Compression::of(input)?;
+    Ok(match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(input))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(input)?)),
+        Compression::Xz => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(
+            input,
+        ))),
+    })
+}
+
+/// Opens a file, or the standard input for `-`, decompressing it if needed
+pub fn open(path: &str) -> Result<Box<dyn BufRead>> {
+    if path == inputs::STDIN {
+        return reader(BufReader::new(io::stdin())).map_err(|e| Error::input("stdin", e));
+    }
+    let file = File::open(path).map_err(|e| Error::input(path, e))?;
+    reader(BufReader::new(file)).map_err(|e| Error::input(path, e))
+}
+
+/// Reads a whole file as text, decompressing it if needed
+pub fn read_to_string(path: &str) -> Result<String> {
+    let mut text = String::new();
+    open(path)?
+        .read_to_string(&mut text)
+        .map_err(|e| Error::input(path, e))?;
+    Ok(text)
+}
+
+/// Reads a whole file, decompressing it if needed
+pub fn read_to_end(path: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(path)?
+        .read_to_end(&mut data)
+        .map_err(|e| Error::input(path, e))?;
+    Ok(data)
+}
+
+/// A stream compressed as it is written
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(compression: Compression, w: W) -> io::Result<Encoder<W>> {
+        Ok(match compression {
+            Compression::None => Encoder::None(w),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(w, 0)?),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(w, 6)),
+        })
+    }
+
+    /// Writes the end of the compressed stream
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+        }
+    }
+}
+
+/// Writes to the output through the compression and ends the compressed stream
+pub fn compress<W: Write>(
+    compression: Compression,
+    w: W,
+    write: impl FnOnce(&mut BufWriter<Encoder<W>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut encoder = BufWriter::with_capacity(BUFFER, Encoder::new(compression, w)?);
+    write(&mut encoder)?;
+    encoder.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests_compression {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let text = "ads.fb.com\ntracker.net\n".repeat(100);
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Xz,
+        ] {
+            let mut compressed = Vec::new();
+            compress(compression, &mut compressed, |w| {
+                w.write_all(text.as_bytes())
+            })
+            .unwrap();
+            assert_eq!(compression, Compression::detect(&compressed));
+            let mut read = String::new();
+            reader(&compressed[..])
+                .unwrap()
+                .read_to_string(&mut read)
+                .unwrap();
+            assert_eq!(text, read, "{}", compression);
+            // from a pipe giving a byte at a time
+            let mut read = String::new();
+            reader(BufReader::with_capacity(1, &compressed[..]))
+                .unwrap()
+                .read_to_string(&mut read)
+                .unwrap();
+            assert_eq!(text, read, "{} a byte at a time", compression);
+        }
+        // concatenated gzip streams, as zcat reads them
+        let mut twice = Vec::new();
+        compress(Compression::Gzip, &mut twice, |w| w.write_all(b"a.com\n")).unwrap();
+        compress(Compression::Gzip, &mut twice, |w| w.write_all(b"b.com\n")).unwrap();
+        let mut read = String::new();
+        reader(&twice[..])
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!("a.com\nb.com\n", read);
+        assert_eq!(Ok(Compression::Zstd), "zst".parse());
+    }
+}
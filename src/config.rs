@@ -0,0 +1,275 @@
+//! The configuration file listing everything one run of the build command reads and writes
+//!
+//! ```toml
+//! personal = ["hosts_blocked.txt"]
+//! block = ["lists.d/*.list"]
+//! whitelist = ["domains.whitelisted"]
+//! public_suffix_list = "public_suffix_list.dat"
+//! sort = "reversed"
+//! state_dir = "state"
+//! memory_limit = 300
+//!
+//! [resolver]
+//! server = "8.8.8.8:53"
+//!
+//! [guardrails]
+//! max_change = 50
+//!
+//! [[output]]
+//! format = "plain"
+//! path = "domains.blocked"
+//!
+//! [[output]]
+//! format = "bind"
+//! path = "rpz.db"
+//!
+//! [[output]]
+//! format = "plain"
+//! path = "domains.blocked.gz"
+//! compress = "gzip"
+//! ```
+//!
+//! Relative paths are relative to the directory of the configuration file. The lists
+//! can also be directories or globs, their files are read in the order of their names.
+
+use crate::blocklist::BlockListBuilder;
+use crate::compression::Compression;
+use crate::dns_resolver;
+use crate::error::{Error, Result};
+use crate::guard::Guardrails;
+use crate::inputs;
+use crate::low_memory::MIN_MEMORY_LIMIT;
+use crate::writers::{Format, Order};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Personal lists of domains to block, their entries are credited to them
+    /// when they also appear in the other lists
+    pub personal: Vec<String>,
+    /// Lists of domains to block, e.g. the ones fetched by getlists.sh
+    pub block: Vec<String>,
+    /// Lists of domains that are never blocked
+    pub whitelist: Vec<String>,
+    /// Copy of the Public Suffix List
+    pub public_suffix_list: Option<String>,
+    /// File to write the input lines that are not valid domain names to
+    pub rejects: Option<String>,
+    /// Order of the domains in the outputs
+    pub sort: Order,
+    /// Number of parts the domains are folded in, in parallel, the number of cores by default
+    pub shards: Option<usize>,
+    /// Directory keeping what a build parsed, the next build only parses the lists that changed
+    pub state_dir: Option<String>,
+    /// Megabytes the build may use, the lists are then sorted and folded on disk
+    pub memory_limit: Option<u64>,
+    pub resolver: ResolverConfig,
+    pub guardrails: GuardrailsConfig,
+    #[serde(rename = "output")]
+    pub outputs: Vec<OutputConfig>,
+}
+
+/// How the CNAMEs of the whitelisted domains are resolved
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    pub resolve_cnames: bool,
+    /// address:port of the DNS server
+    pub server: String,
+    /// seconds to wait for the answers
+    pub timeout: u64,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> ResolverConfig {
+        ResolverConfig {
+            resolve_cnames: true,
+            server: dns_resolver::DEFAULT_SERVER.to_string(),
+            timeout: dns_resolver::DEFAULT_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+/// The sanity checks run before replacing the outputs, none unless asked for
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GuardrailsConfig {
+    /// Largest allowed change in the number of entries, in percent, 0 to disable
+    pub max_change: f64,
+    /// File with domains that must keep resolving
+    pub protect: Option<String>,
+    /// Write the outputs even if they fail the checks
+    pub force: bool,
+}
+
+/// A file to write the block list to
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: Format,
+    pub path: String,
+    /// Number of previous versions to keep for rollback
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// gzip, zstd or xz to compress the output
+    #[serde(default)]
+    pub compress: Compression,
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+impl Config {
+    /// Reads the configuration file
+    pub fn from_file(path: &str) -> Result<Config> {
+        let text = fs::read_to_string(path).map_err(|e| Error::input(path, e))?;
+        Config::parse(path, &text)
+    }
+
+    /// Parses the configuration, the path is used for the error messages and
+    /// to resolve the relative paths
+    pub fn parse(path: &str, text: &str) -> Result<Config> {
+        let error = |message: String| Error::Config {
+            path: path.to_string(),
+            message,
+        };
+        let mut config: Config = toml::from_str(text).map_err(|e| error(e.to_string()))?;
+        if config.block.is_empty() && config.personal.is_empty() {
+            return Err(error("no block or personal lists".to_string()));
+        }
+        if let Some(mb) = config.memory_limit.filter(|mb| *mb < MIN_MEMORY_LIMIT) {
+            return Err(error(format!(
+                "memory_limit is {} MB, at least {} are needed",
+                mb, MIN_MEMORY_LIMIT
+            )));
+        }
+        let compressed_packed =
+            |o: &&OutputConfig| o.format == Format::Packed && o.compress != Compression::None;
+        if let Some(output) = config.outputs.iter().find(compressed_packed) {
+            return Err(error(format!(
+                "the packed output {} can't be compressed, --index maps it as it is",
+                output.path
+            )));
+        }
+
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let relative_to_dir = |p: &mut String| {
+            if p != inputs::STDIN && Path::new(p.as_str()).is_relative() {
+                *p = dir.join(&*p).to_string_lossy().into_owned();
+            }
+        };
+        config
+            .personal
+            .iter_mut()
+            .chain(config.block.iter_mut())
+            .chain(config.whitelist.iter_mut())
+            .chain(config.public_suffix_list.iter_mut())
+            .chain(config.rejects.iter_mut())
+            .chain(config.state_dir.iter_mut())
+            .chain(config.guardrails.protect.iter_mut())
+            .chain(config.outputs.iter_mut().map(|o| &mut o.path))
+            .for_each(relative_to_dir);
+        Ok(config)
+    }
+
+    /// The block list files, the personal ones first so their entries are credited to them
+    pub fn block_files(&self) -> Result<Vec<String>> {
+        inputs::expand_all(self.personal.iter().chain(&self.block))
+    }
+
+    /// The whitelist files
+    pub fn whitelist_files(&self) -> Result<Vec<String>> {
+        inputs::expand_all(&self.whitelist)
+    }
+
+    /// Reads the whitelists and the settings into a builder, without the block lists
+    pub fn base_builder(&self) -> Result<BlockListBuilder> {
+        let mut builder = BlockListBuilder::new()
+            .resolve_cnames(self.resolver.resolve_cnames)
+            .resolver_server(self.resolver.server.as_str())
+            .resolver_timeout(Duration::from_secs(self.resolver.timeout));
+        if let Some(f) = &self.public_suffix_list {
+            builder = builder.public_suffix_file(f)?;
+        }
+        if let Some(shards) = self.shards {
+            builder = builder.shards(shards);
+        }
+        if let Some(dir) = &self.state_dir {
+            builder = builder.state_dir(dir);
+        }
+        for f in self.whitelist_files()? {
+            builder = builder.whitelist_file(&f)?;
+        }
+        Ok(builder)
+    }
+
+    /// The sanity checks for the outputs
+    pub fn guardrails(&self) -> Result<Guardrails> {
+        let mut guardrails = Guardrails {
+            max_change: Some(self.guardrails.max_change).filter(|m| *m > 0.0),
+            force: self.guardrails.force,
+            ..Default::default()
+        };
+        if let Some(f) = &self.guardrails.protect {
+            guardrails.protect_file(f)?;
+        }
+        Ok(guardrails)
+    }
+}
+
+#[cfg(test)]
+mod tests_config {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let config = Config::parse(
+            "/etc/dns-block/dns-block.toml",
+            indoc::indoc! {r#"
+                personal = ["hosts_blocked.txt"]
+                block = ["/var/lib/dns-block/concatenated.list"]
+
+                [resolver]
+                resolve_cnames = false
+
+                [[output]]
+                format = "plain"
+                path = "domains.blocked"
+
+                [[output]]
+                format = "rpz"
+                path = "rpz.db"
+                keep = 1
+                compress = "zstd"
+            "#},
+        )
+        .unwrap();
+        assert_eq!(vec!["/etc/dns-block/hosts_blocked.txt"], config.personal);
+        assert_eq!(vec!["/var/lib/dns-block/concatenated.list"], config.block);
+        assert!(!config.resolver.resolve_cnames);
+        assert_eq!("8.8.8.8:53", config.resolver.server);
+        assert_eq!(None, config.guardrails().unwrap().max_change);
+        assert_eq!(2, config.outputs.len());
+        assert_eq!(Format::Plain, config.outputs[0].format);
+        assert_eq!(3, config.outputs[0].keep);
+        assert_eq!(Format::Bind, config.outputs[1].format);
+        assert_eq!(Compression::None, config.outputs[0].compress);
+        assert_eq!(Compression::Zstd, config.outputs[1].compress);
+        assert_eq!("/etc/dns-block/rpz.db", config.outputs[1].path);
+
+        let e = Config::parse("dns-block.toml", "block = [\"a\"]\nblok = [\"b\"]\n").unwrap_err();
+        assert_eq!(78, e.exit_code());
+        assert!(e.to_string().contains("unknown field `blok`"));
+        assert!(Config::parse("dns-block.toml", "").is_err());
+        let e = Config::parse("dns-block.toml", "block = [\"a\"]\nmemory_limit = 8\n").unwrap_err();
+        assert!(e.to_string().contains("at least 16"));
+        let packed =
+            "block = [\"a\"]\n[[output]]\nformat = \"packed\"\npath = \"i\"\ncompress = \"xz\"\n";
+        assert!(Config::parse("dns-block.toml", packed).is_err());
+    }
+}
@@ -1,8 +1,15 @@
-use std::net::UdpSocket;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
 use std::thread;
+use std::time::Duration;
 
 use log::*;
 
+/// Public server the CNAMEs are resolved with, unless configured otherwise
+pub const DEFAULT_SERVER: &str = "8.8.8.8:53";
+/// How long to wait for an answer before giving up on the remaining ones
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn write(n: u16, vec: &mut [u8], index: usize) {
     let be = n.to_be_bytes();
     vec[index] = be[0];
@@ -22,7 +29,7 @@ fn extract_name(buf: &[u8], start: usize) -> String {
             crt = u16::from_be_bytes([(len - 192) as u8, buf[crt + 1]]) as usize;
             len = buf[crt] as usize;
         }
-        res.push_str(std::str::from_utf8(&buf[crt + 1..=crt + len]).unwrap());
+        res.push_str(&String::from_utf8_lossy(&buf[crt + 1..=crt + len]));
         crt += len + 1;
         len = buf[crt] as usize;
         if len != 0 {
@@ -108,26 +115,38 @@ fn compute_url_length(buf: &[u8], start: usize) -> usize {
     }
 }
 
-pub fn resolve_domain(domains_str: &[&str], result: &mut Vec<String>) -> std::io::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:6913").expect("couldn't bind to address");
-    socket
-        .connect("8.8.8.8:53")
-        .expect("connect function failed");
+pub fn resolve_domain(
+    server: &str,
+    timeout: Duration,
+    domains_str: &[&str],
+    result: &mut Vec<String>,
+) -> io::Result<()> {
+    let address = server.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{server}: no address"))
+    })?;
+    // any free port, of the address family of the server
+    let local = if address.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(address)?;
+    socket.set_read_timeout(Some(timeout))?;
 
     let domains: Vec<String> = domains_str.iter().map(|s| String::from(*s)).collect();
     let domain_count = domains.len();
     let send_socket = socket.try_clone()?;
-    let handle = thread::spawn(move || {
+    let handle = thread::spawn(move || -> std::io::Result<()> {
         let id: u16 = std::process::id() as u16;
         for domain in domains {
             let request = create_request(domain.as_str(), id);
             //id += 1;
             //fs::write("req.bin", &request)?;
 
-            send_socket
-                .send(&request)
-                .expect("could not send the message");
+            send_socket.send(&request)?;
         }
+        Ok(())
     });
 
     debug!("The DNS requests have been sent, now we deal with the answers");
@@ -135,13 +154,30 @@ pub fn resolve_domain(domains_str: &[&str], result: &mut Vec<String>) -> std::io
     let mut resp = [0; 512];
     for _x in 0..domain_count {
         debug!("Waiting for DNS answer {}", _x);
-        let received = socket.recv(&mut resp)?;
+        let received = match socket.recv(&mut resp) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                warn!(
+                    "No answer from {} for {} of the whitelisted domains",
+                    server,
+                    domain_count - _x
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        };
         //fs::write("answer.bin", &resp[0 ..received])?;
 
         extract_data(&resp[0..received], result);
     }
-    handle.join().unwrap();
-    Ok(())
+    handle
+        .join()
+        .map_err(|_| std::io::Error::other("the DNS sender thread panicked"))?
 }
 
 #[cfg(test)]
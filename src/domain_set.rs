@@ -0,0 +1,312 @@
+//! Domains kept in a trie by their labels from the top level domain down, so a name
+//! and all its parents are looked up in one walk
+//!
+//! The names are added sorted by [`cmp_labels`], a parent comes right before its
+//! subdomains and folds them in as they are added. Only the labels shared by several
+//! entries get a node, the rest of an entry's labels are read from its name.
+
+use crate::sub_domains::{cmp_labels, labels};
+use std::cmp::Ordering;
+
+/// Marks a child that is an entry, the other children are nodes
+const ENTRY: u32 = 1 << 31;
+
+/// A label shared by several entries, its children are a run of the children
+/// vector sorted by their labels
+#[derive(Default)]
+struct Node {
+    /// id of the interned label
+    label: u32,
+    first: u32,
+    len: u32,
+}
+
+/// What happened to a name added to a [`DomainSet`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Insert<'a> {
+    /// It's new
+    Added,
+    /// It was already there
+    Duplicate,
+    /// A parent is already there and covers it, the parent is returned
+    Covered(&'a str),
+}
+
+/// A set of domains where no entry is a subdomain of another one
+pub struct DomainSet<'a, V> {
+    /// sorted, without repeats
+    labels: Vec<&'a str>,
+    nodes: Vec<Node>,
+    root: Node,
+    /// the children of all the nodes, a node's are next to each other
+    children: Vec<u32>,
+    /// sorted by their labels
+    entries: Vec<(&'a str, V)>,
+}
+
+impl<V> Default for DomainSet<'_, V> {
+    fn default() -> Self {
+        DomainSet {
+            labels: Vec::new(),
+            nodes: Vec::new(),
+            root: Node::default(),
+            children: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<'a, V> DomainSet<'a, V> {
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The label a child is sorted by, the one at depth in the name of an entry
+    fn label(&self, child: u32, depth: usize) -> &'a str {
+        if child & ENTRY == 0 {
+            self.labels[self.nodes[child as usize].label as usize]
+        } else {
+            let (name, _) = &self.entries[(child & !ENTRY) as usize];
+            labels(name).nth(depth).unwrap_or_default()
+        }
+    }
+
+    /// Returns the entry for the name or for one of its parents, there is at most one
+    pub fn find(&self, name: &str) -> Option<(&'a str, &V)> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let mut node = &self.root;
+        for (depth, label) in labels(name).enumerate() {
+            let children = &self.children[node.first as usize..(node.first + node.len) as usize];
+            let i = children
+                .binary_search_by(|child| self.label(*child, depth).cmp(label))
+                .ok()?;
+            let child = children[i];
+            if child & ENTRY == 0 {
+                node = &self.nodes[child as usize];
+                continue;
+            }
+            let (entry, value) = &self.entries[(child & !ENTRY) as usize];
+            let covered = name == *entry
+                || name
+                    .strip_suffix(entry)
+                    .is_some_and(|sub| sub.ends_with('.'));
+            return covered.then_some((*entry, value));
+        }
+        None
+    }
+
+    /// The entries, sorted by their labels from the top level domain down
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &V)> + '_ {
+        self.entries.iter().map(|(name, value)| (*name, value))
+    }
+}
+
+impl<'a> Insert<'a> {
+    /// What adding the name after the last entry of a set sorted by [`cmp_labels`] does,
+    /// the last entry is the only one that can cover it
+    pub fn after(last: Option<&'a str>, name: &str) -> Insert<'a> {
+        let Some(last) = last else {
+            return Insert::Added;
+        };
+        debug_assert_ne!(Ordering::Greater, cmp_labels(last, name));
+        let mut name_labels = labels(name);
+        if !labels(last).all(|label| name_labels.next() == Some(label)) {
+            Insert::Added
+        } else if name_labels.next().is_none() {
+            Insert::Duplicate
+        } else {
+            Insert::Covered(last)
+        }
+    }
+}
+
+/// Names added in any order, sorted first, the first of the same names is kept
+impl<'a, V> FromIterator<(&'a str, V)> for DomainSet<'a, V> {
+    fn from_iter<I: IntoIterator<Item = (&'a str, V)>>(iter: I) -> Self {
+        let mut entries: Vec<(&'a str, V)> = iter.into_iter().collect();
+        entries.sort_by(|a, b| cmp_labels(a.0, b.0));
+        entries.dedup_by(|name, kept| Insert::after(Some(kept.0), name.0) != Insert::Added);
+        DomainSet::from_sorted(entries)
+    }
+}
+
+impl<'a, V> DomainSet<'a, V> {
+    /// Makes the trie over entries sorted by [`cmp_labels`], none of them covering another,
+    /// they are kept as they are
+    pub fn from_sorted(entries: Vec<(&'a str, V)>) -> DomainSet<'a, V> {
+        // the labels an entry has in common with the one before it
+        let shared: Vec<u8> = entries
+            .windows(2)
+            .map(|pair| {
+                debug_assert_eq!(Insert::Added, Insert::after(Some(pair[0].0), pair[1].0));
+                let common = labels(pair[0].0).zip(labels(pair[1].0));
+                common.take_while(|(a, b)| a == b).count() as u8
+            })
+            .collect();
+        // a node for each label an entry shares with the one before it beyond the last one's
+        let mut depth = 0;
+        let nodes = shared.iter().fold(0, |nodes, shared| {
+            let added = shared.saturating_sub(depth) as usize;
+            depth = *shared;
+            nodes + added
+        });
+
+        let mut set = DomainSet {
+            nodes: Vec::with_capacity(nodes),
+            children: Vec::with_capacity(entries.len() + nodes),
+            entries,
+            ..DomainSet::default()
+        };
+        // while building, a node's label is the index of an entry below it, and where in its
+        // name the label is
+        let mut spans: Vec<(u8, u8)> = Vec::with_capacity(nodes);
+        // the nodes on the path of the last entry, from the top level domain down, and where
+        // their children start in pending
+        let mut open: Vec<(u32, (u8, u8), usize)> = Vec::new();
+        // the children of the root and of the open nodes, each after its parent's
+        let mut pending: Vec<u32> = Vec::new();
+        let mut close = |open: &mut Vec<(u32, (u8, u8), usize)>, pending: &mut Vec<u32>| {
+            let (entry, span, start) = open.pop().unwrap();
+            spans.push(span);
+            set.nodes.push(Node {
+                label: entry,
+                first: set.children.len() as u32,
+                len: (pending.len() - start) as u32,
+            });
+            set.children.extend(pending.drain(start..));
+            pending.push(set.nodes.len() as u32 - 1);
+        };
+        for i in 0..set.entries.len() {
+            if i > 0 {
+                let shared = shared[i - 1] as usize;
+                // the last entry hangs off the deepest open node
+                if shared > open.len() {
+                    let last = set.entries[i - 1].0;
+                    let entry = pending.pop().unwrap();
+                    for label in labels(last).take(shared).skip(open.len()) {
+                        let start = label.as_ptr() as usize - last.as_ptr() as usize;
+                        let span = (start as u8, label.len() as u8);
+                        open.push((i as u32 - 1, span, pending.len()));
+                    }
+                    pending.push(entry);
+                }
+                while open.len() > shared {
+                    close(&mut open, &mut pending);
+                }
+            }
+            pending.push(i as u32 | ENTRY);
+        }
+        while !open.is_empty() {
+            close(&mut open, &mut pending);
+        }
+        set.root = Node {
+            label: 0,
+            first: set.children.len() as u32,
+            len: pending.len() as u32,
+        };
+        set.children.append(&mut pending);
+
+        // the labels are interned in their order
+        let node_label = |nodes: &[Node], node: u32| {
+            let node = node as usize;
+            let name = set.entries[nodes[node].label as usize].0;
+            let (start, len) = spans[node];
+            &name[start as usize..start as usize + len as usize]
+        };
+        let mut by_label: Vec<u32> = (0..set.nodes.len() as u32).collect();
+        by_label.sort_unstable_by_key(|node| node_label(&set.nodes, *node));
+        let distinct = by_label
+            .windows(2)
+            .filter(|pair| node_label(&set.nodes, pair[0]) != node_label(&set.nodes, pair[1]))
+            .count();
+        let mut interned = Vec::with_capacity(distinct + 1);
+        for node in by_label {
+            let label = node_label(&set.nodes, node);
+            if interned.last() != Some(&label) {
+                interned.push(label);
+            }
+            set.nodes[node as usize].label = interned.len() as u32 - 1;
+        }
+        set.labels = interned;
+        set.entries.shrink_to_fit();
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests_domain_set {
+    use super::*;
+
+    #[test]
+    fn find_test() {
+        let mut names = vec![
+            "x.ads.fb.com",
+            "fb.net",
+            "ads.fb.com",
+            "y.cdn.fb.com",
+            "ads.fb.com",
+            "x.cdn.fb.com",
+            "cdn.fb.org",
+        ];
+        names.sort_by(|a, b| cmp_labels(a, b));
+        let mut last = None;
+        let mut entries = Vec::new();
+        let mut inserted = Vec::new();
+        for name in names {
+            let insert = Insert::after(last, name);
+            if insert == Insert::Added {
+                entries.push((name, 1));
+                last = Some(name);
+            }
+            inserted.push(insert);
+        }
+        assert_eq!(
+            vec![
+                Insert::Added,
+                Insert::Duplicate,
+                Insert::Covered("ads.fb.com"),
+                Insert::Added,
+                Insert::Added,
+                Insert::Added,
+                Insert::Added,
+            ],
+            inserted
+        );
+        let set = DomainSet::from_sorted(entries);
+        assert_eq!(5, set.len());
+
+        assert_eq!(Some(("ads.fb.com", &1)), set.find("z.x.ads.fb.com"));
+        assert_eq!(Some(("ads.fb.com", &1)), set.find("ads.fb.com."));
+        assert_eq!(Some(("x.cdn.fb.com", &1)), set.find("x.cdn.fb.com"));
+        assert_eq!(None, set.find("cdn.fb.com"));
+        assert_eq!(None, set.find("xx.cdn.fb.com"));
+        assert_eq!(None, set.find("fb.com"));
+        assert_eq!(None, set.find("com"));
+        assert_eq!(None, set.find("fb.org"));
+
+        let entries: Vec<&str> = set.iter().map(|(entry, _)| entry).collect();
+        assert_eq!(
+            vec![
+                "ads.fb.com",
+                "x.cdn.fb.com",
+                "y.cdn.fb.com",
+                "fb.net",
+                "cdn.fb.org"
+            ],
+            entries
+        );
+
+        // in any order, the first of the same names is kept and a top level domain covers its names
+        let set: DomainSet<_> = [("x.ads.fb.com", 1), ("com", 2), ("fb.net", 3), ("com", 4)]
+            .into_iter()
+            .collect();
+        assert_eq!(2, set.len());
+        assert_eq!(Some(("com", &2)), set.find("x.ads.fb.com"));
+        assert_eq!(None, DomainSet::<usize>::default().find("com"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors of the library and the exit codes the command line tool reports them with
+
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while building or writing a block list
+#[derive(Debug)]
+pub enum Error {
+    /// An input file can't be read
+    Input { path: String, source: io::Error },
+    /// An input or parameter doesn't make sense, e.g. a bad client filter
+    InvalidInput(String),
+    /// The CNAMEs of the whitelisted domains can't be resolved
+    Resolver(io::Error),
+    /// An output file can't be written
+    Output { path: String, source: io::Error },
+    /// The block list failed the sanity checks and was not written
+    Suspicious(String),
+    /// The configuration file doesn't make sense
+    Config { path: String, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn input(path: impl Into<String>, source: io::Error) -> Error {
+        Error::Input {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn output(path: impl Into<String>, source: io::Error) -> Error {
+        Error::Output {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Exit code for the command line, following the BSD sysexits.h conventions
+    /// so scripts can tell the failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_NOINPUT
+            Error::Input { .. } => 66,
+            // EX_USAGE
+            Error::InvalidInput(_) => 64,
+            // EX_DATAERR
+            Error::Suspicious(_) => 65,
+            // EX_UNAVAILABLE
+            Error::Resolver(_) => 69,
+            // EX_IOERR
+            Error::Output { .. } => 74,
+            // EX_CONFIG
+            Error::Config { .. } => 78,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Input { path, source } => write!(f, "can't read {}: {}", path, source),
+            Error::InvalidInput(message) | Error::Suspicious(message) => f.write_str(message),
+            Error::Resolver(source) => {
+                write!(f, "can't resolve the whitelisted domains: {}", source)
+            }
+            Error::Output { path, source } => write!(f, "can't write {}: {}", path, source),
+            Error::Config { path, message } => {
+                write!(f, "bad configuration in {}: {}", path, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Input { source, .. } | Error::Output { source, .. } => Some(source),
+            Error::Resolver(source) => Some(source),
+            Error::InvalidInput(_) | Error::Suspicious(_) | Error::Config { .. } => None,
+        }
+    }
+}
+
+#[test]
+fn display_test() {
+    let e = Error::input(
+        "domains.whitelisted",
+        io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"),
+    );
+    assert_eq!(
+        "can't read domains.whitelisted: Permission denied",
+        e.to_string()
+    );
+    assert_eq!(66, e.exit_code());
+}
@@ -1,29 +1,87 @@
-use crate::sub_domains::sub_domain_iterator;
-use fnv::FnvHashSet as HashSet;
-use log::*;
-use std::io::{self, Write};
-
-fn is_domain_blocked_by_index(domain: &str, index: &HashSet<&str>) -> bool {
-    for seg in sub_domain_iterator(domain, 1) {
-        if index.contains(seg) {
-            return true;
+use crate::client_filter::ClientFilter;
+use crate::compression;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// How pipe mode shows the queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The original log line, or `client domain blocked` for blocked queries
+    Text,
+    /// One json object per query
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<OutputFormat, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("{s}: unknown output format, use text or json")),
         }
     }
-    false
 }
 
-fn is_domain_blocked(
-    domain: &str,
-    blacklist_com: &HashSet<&str>,
-    blacklist_net: &HashSet<&str>,
-) -> bool {
-    if domain.ends_with("com") {
-        is_domain_blocked_by_index(domain, blacklist_com)
-    } else {
-        is_domain_blocked_by_index(domain, blacklist_net)
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        })
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryResult {
+    Blocked,
+    Allowed,
+    Whitelisted,
+}
+
+/// Answers the queries, either a block list built from the lists or a packed index
+pub trait Lookup {
+    /// Decides what happens to a query for the domain and record type, returns the entry
+    /// that blocked, would have blocked or allowed it and the name of the list it came from
+    fn check(&self, domain: &str, qtype: Option<&str>) -> (QueryResult, Option<(&str, &str)>);
+
+    /// Every name with an entry, blocked or whitelisted, in no particular order
+    fn names(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    /// Checks if an entry for some record types only blocks or allows the domain,
+    /// a check for other types finds nothing even though the entry is there
+    fn has_typed_entry(&self, domain: &str) -> bool;
+}
+
+/// The interesting parts of a Bind9 query log line
+#[derive(Debug, PartialEq, Eq)]
+pub struct Query<'a> {
+    pub timestamp: Option<&'a str>,
+    pub client: &'a str,
+    pub name: &'a str,
+    pub qtype: Option<&'a str>,
+    pub view: Option<&'a str>,
+}
+
+/// One line of the json output
+#[derive(Serialize)]
+struct QueryRecord<'a> {
+    timestamp: Option<&'a str>,
+    client: &'a str,
+    query: &'a str,
+    #[serde(rename = "type")]
+    qtype: Option<&'a str>,
+    view: Option<&'a str>,
+    result: QueryResult,
+    entry: Option<&'a str>,
+    source: Option<&'a str>,
+}
+
 fn extract<'a>(line: &'a str, pref: &str, suf: &str) -> Option<&'a str> {
     if let Some(index_pref) = line.find(pref) {
         let start = index_pref + pref.len();
@@ -35,36 +93,73 @@ fn extract<'a>(line: &'a str, pref: &str, suf: &str) -> Option<&'a str> {
     None
 }
 
-pub fn filter(
-    blacklist_com: &HashSet<&str>,
-    blacklist_net: &HashSet<&str>,
-    filter_parameter: Option<&str>,
-) -> io::Result<()> {
-    debug!("Filter for client ips: {:#?}", filter_parameter);
-    let mut input = String::new();
+/// Extracts the client address, newer Bind9 versions log the client object
+/// before the address, e.g. `client @0x7f3a5c0e1b68 2001:db8::1#5353`
+fn extract_client(line: &str) -> Option<&str> {
+    extract(line, "client ", "#").and_then(|s| s.split_whitespace().next_back())
+}
+
+/// Parses a query log line like
+/// `20-Jan-2021 10:10:10.536 client 10.0.0.30#7216 (mydomain.com): view internal: query: mydomain.com IN A + (10.0.0.12)`
+pub fn parse_query(line: &str) -> Option<Query<'_>> {
+    let client = extract_client(line)?;
+    let query_start = line.find("query: ")? + "query: ".len();
+    let mut query = line[query_start..].split_whitespace();
+    let name = query.next()?;
+    // class and type follow the name, e.g. IN A
+    let qtype = query.nth(1);
+    let timestamp = match line.find(" client ") {
+        Some(end) if end > 0 => Some(line[0..end].trim()),
+        _ => None,
+    };
+    Some(Query {
+        timestamp,
+        client,
+        name,
+        qtype,
+        view: extract(line, "view ", ":"),
+    })
+}
 
+/// Copies the query log from stdin to stdout, marking the blocked queries
+pub fn filter(
+    blocklist: &impl Lookup,
+    client_filter: &ClientFilter,
+    output: OutputFormat,
+) -> Result<()> {
     let stdout = io::stdout();
-    let mut handle = stdout.lock();
+    let mut input =
+        compression::reader(io::stdin().lock()).map_err(|e| Error::input("stdin", e))?;
+    filter_lines(
+        blocklist,
+        client_filter,
+        output,
+        &mut input,
+        &mut stdout.lock(),
+    )
+}
 
-    let ip_filter: HashSet<&str> = match filter_parameter {
-        Some(filter) => filter.split(',').collect::<HashSet<&str>>(),
-        None => HashSet::with_capacity_and_hasher(0, Default::default()),
-    };
+/// Copies a query log, marking the blocked queries
+pub fn filter_lines(
+    blocklist: &impl Lookup,
+    client_filter: &ClientFilter,
+    output: OutputFormat,
+    reader: &mut impl BufRead,
+    handle: &mut impl Write,
+) -> Result<()> {
+    let mut input = String::new();
 
     loop {
-        let n = io::stdin().read_line(&mut input)?;
+        let n = reader
+            .read_line(&mut input)
+            .map_err(|e| Error::input("stdin", e))?;
         if n == 0 {
             return Ok(());
         }
-        let domain_opt = extract(&input, "query: ", " ");
-        let client_opt = extract(&input, "client ", "#");
-        if let (Some(domain), Some(client)) = (domain_opt, client_opt) {
-            if ip_filter.is_empty() || ip_filter.contains(&client) {
-                if !is_domain_blocked(domain, blacklist_com, blacklist_net) {
-                    handle.write_all(input.as_bytes())?;
-                } else {
-                    handle.write_fmt(format_args!("{} {} {}\n", &client, &domain, "blocked"))?;
-                }
+        if let Some(query) = parse_query(&input) {
+            if client_filter.matches(query.client) {
+                write_query(handle, blocklist, &input, &query, output)
+                    .map_err(|e| Error::output("stdout", e))?;
             }
         }
 
@@ -72,15 +167,75 @@ pub fn filter(
     }
 }
 
+fn write_query(
+    handle: &mut impl Write,
+    blocklist: &impl Lookup,
+    line: &str,
+    query: &Query,
+    output: OutputFormat,
+) -> io::Result<()> {
+    let (result, found) = blocklist.check(query.name, query.qtype);
+    match output {
+        OutputFormat::Text => {
+            if result != QueryResult::Blocked {
+                handle.write_all(line.as_bytes())
+            } else {
+                handle.write_fmt(format_args!(
+                    "{} {} {}\n",
+                    &query.client, &query.name, "blocked"
+                ))
+            }
+        }
+        OutputFormat::Json => {
+            let record = QueryRecord {
+                timestamp: query.timestamp,
+                client: query.client,
+                query: query.name,
+                qtype: query.qtype,
+                view: query.view,
+                result,
+                entry: found.map(|(entry, _)| entry),
+                source: found.map(|(_, source)| source),
+            };
+            serde_json::to_writer(&mut *handle, &record)?;
+            handle.write_all(b"\n")
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests_filter {
     #[test]
     fn extraction_test() {
         let line = "20-Jan-2021 10:10:10.536 client 10.0.0.30#7216 (mydomain.com): view internal: query: mydomain.com IN A + (10.0.0.12)";
-        assert_eq!("10.0.0.30", super::extract(&line, "client ", "#").unwrap());
+        assert_eq!("10.0.0.30", super::extract(line, "client ", "#").unwrap());
         assert_eq!(
             "mydomain.com",
-            super::extract(&line, "query: ", " ").unwrap()
+            super::extract(line, "query: ", " ").unwrap()
+        );
+    }
+
+    #[test]
+    fn client_extraction_test() {
+        let line = "20-Jan-2021 10:10:10.536 client @0x7f3a5c0e1b68 2001:db8::1#5353 (mydomain.com): view internal: query: mydomain.com IN AAAA + (10.0.0.12)";
+        assert_eq!("2001:db8::1", super::extract_client(line).unwrap());
+        let line = "20-Jan-2021 10:10:10.536 client 10.0.0.30#7216 (mydomain.com): query: mydomain.com IN A + (10.0.0.12)";
+        assert_eq!("10.0.0.30", super::extract_client(line).unwrap());
+    }
+
+    #[test]
+    fn query_parsing_test() {
+        let line = "20-Jan-2021 10:10:10.536 client @0x7f3a5c0e1b68 10.0.0.30#7216 (mydomain.com): view internal: query: mydomain.com IN HTTPS + (10.0.0.12)";
+        assert_eq!(
+            super::Query {
+                timestamp: Some("20-Jan-2021 10:10:10.536"),
+                client: "10.0.0.30",
+                name: "mydomain.com",
+                qtype: Some("HTTPS"),
+                view: Some("internal"),
+            },
+            super::parse_query(line).unwrap()
         );
+        assert!(super::parse_query("some other log line").is_none());
     }
 }
@@ -0,0 +1,186 @@
+//! Sanity checks that keep a broken upstream list from being published
+
+use crate::blocklist::BlockList;
+use crate::compression;
+use crate::error::{Error, Result};
+use crate::writers::{self, Format};
+use log::*;
+
+/// How many offending entries are listed for each check
+const MAX_LISTED: usize = 10;
+
+/// What the checks look at, a block list in memory or one folded on disk
+pub trait Checked {
+    /// Number of entries for all record types
+    fn count(&self) -> usize;
+    /// Entries refused for being public suffixes, with the name of their source
+    fn public_suffixes(&self) -> Vec<(&str, &str)>;
+    /// The entry blocking each domain for all record types, with the name of its source
+    fn blocking(&self, domains: &[String]) -> Vec<Option<(String, String)>>;
+}
+
+impl Checked for BlockList<'_> {
+    fn count(&self) -> usize {
+        BlockList::len(self)
+    }
+
+    fn public_suffixes(&self) -> Vec<(&str, &str)> {
+        BlockList::public_suffixes(self).collect()
+    }
+
+    fn blocking(&self, domains: &[String]) -> Vec<Option<(String, String)>> {
+        domains
+            .iter()
+            .map(|d| match self.check(d, None) {
+                (crate::QueryResult::Blocked, found) => {
+                    found.map(|(entry, source)| (entry.to_string(), source.to_string()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The checks to run on a block list before it replaces an output file
+#[derive(Debug, Clone, Default)]
+pub struct Guardrails {
+    /// Largest allowed change in the number of entries compared to the previous
+    /// output, in percent
+    pub max_change: Option<f64>,
+    /// Domains that must keep resolving
+    pub protected: Vec<String>,
+    /// Only warn about the problems found
+    pub force: bool,
+}
+
+impl Guardrails {
+    /// Adds the domains of a file, one per line, to the protected ones
+    pub fn protect_file(&mut self, path: &str) -> Result<()> {
+        let content = compression::read_to_string(path)?;
+        self.protected.extend(
+            content
+                .lines()
+                .filter_map(crate::sub_domains::Domain::new)
+                .map(|d| d.name.to_ascii_lowercase()),
+        );
+        Ok(())
+    }
+
+    /// Checks the block list about to be written to the output file in the given format
+    pub fn check(&self, blocklist: &impl Checked, format: Format, output_file: &str) -> Result<()> {
+        let mut problems = Vec::new();
+
+        // there is nothing to compare with before the first write
+        let previous = match self.max_change {
+            Some(max_change) => writers::count_entries(format, output_file)
+                .ok()
+                .map(|before| (max_change, before)),
+            None => None,
+        };
+        if let Some((max_change, before)) = previous {
+            let after = blocklist.count();
+            if before > 0 {
+                let change = (after as f64 - before as f64) * 100.0 / before as f64;
+                if change.abs() > max_change {
+                    problems.push(format!(
+                        "the number of entries changes by {:.1}%, from {} to {}, more than the allowed {}%",
+                        change, before, after, max_change
+                    ));
+                }
+            }
+        }
+
+        // they were left out of the block list, but a list having them is broken
+        let suffixes = blocklist.public_suffixes();
+        if !suffixes.is_empty() {
+            problems.push(format!(
+                "{} entries are top level domains or public suffixes: {}",
+                suffixes.len(),
+                listing(
+                    suffixes
+                        .iter()
+                        .map(|(entry, source)| format!("{} (from {})", entry, source))
+                )
+            ));
+        }
+
+        let blocked: Vec<String> = self
+            .protected
+            .iter()
+            .zip(blocklist.blocking(&self.protected))
+            .filter_map(|(d, blocking)| {
+                blocking.map(|(entry, source)| format!("{} (by {} from {})", d, entry, source))
+            })
+            .collect();
+        if !blocked.is_empty() {
+            problems.push(format!(
+                "{} protected domains would be blocked: {}",
+                blocked.len(),
+                listing(blocked.iter())
+            ));
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if self.force {
+            for problem in &problems {
+                warn!("Writing {} anyway: {}", output_file, problem);
+            }
+            Ok(())
+        } else {
+            Err(Error::Suspicious(format!(
+                "refusing to write {}, use --force to override:\n{}",
+                output_file,
+                problems.join("\n")
+            )))
+        }
+    }
+}
+
+fn listing<T: AsRef<str>>(items: impl ExactSizeIterator<Item = T>) -> String {
+    let more = items.len().saturating_sub(MAX_LISTED);
+    let mut list = items
+        .take(MAX_LISTED)
+        .map(|i| i.as_ref().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if more > 0 {
+        list.push_str(&format!(" and {} more", more));
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests_guard {
+    use super::*;
+    use crate::BlockListBuilder;
+
+    #[test]
+    fn check_test() {
+        let builder = BlockListBuilder::new()
+            .block_source("bad list", "com.\nco.uk\nads.fb.com\n".to_string());
+        let blocklist = builder.build().unwrap();
+        let guardrails = Guardrails {
+            max_change: Some(10.0),
+            protected: vec!["www.ads.fb.com".to_string(), "mydomain.com".to_string()],
+            force: false,
+        };
+        let e = guardrails
+            .check(&blocklist, Format::Plain, "/nonexistent/simple.blocked")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("2 entries are top level domains or public suffixes"));
+        assert!(e.contains("co.uk (from bad list)"));
+        assert!(e.contains("www.ads.fb.com (by ads.fb.com from bad list)"));
+        assert!(!e.contains("mydomain.com"));
+
+        let forced = Guardrails {
+            force: true,
+            ..guardrails
+        };
+        assert!(forced
+            .check(&blocklist, Format::Plain, "/nonexistent/simple.blocked")
+            .is_ok());
+    }
+}
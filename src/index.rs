@@ -0,0 +1,138 @@
+use crate::domain_set::DomainSet;
+use crate::qtype::QTypes;
+use crate::sub_domains::{labels, sub_domain_iterator};
+use fnv::FnvHashMap as HashMap;
+use fnv::FnvHasher;
+use std::hash::{Hash, Hasher};
+
+/// The shard a domain goes to out of `shards`, by its last two labels, so a domain
+/// and its subdomains always end up in the same one
+pub fn shard(domain: &str, shards: usize) -> usize {
+    let mut hasher = FnvHasher::default();
+    labels(domain)
+        .take(2)
+        .for_each(|label| label.hash(&mut hasher));
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// The blocked domains of one part of the input and the domains kept out of it
+/// by the whitelist, each with the index of the source list it came from
+pub struct Index<'a> {
+    /// no entry is a subdomain of another one, those are folded into their parent
+    pub blocked: DomainSet<'a, usize>,
+    pub whitelisted: HashMap<&'a str, usize>,
+    /// entries blocked only for some record types, they cover neither their subdomains
+    /// nor are they swallowed by them
+    pub typed_blocked: HashMap<&'a str, (QTypes, usize)>,
+    /// whitelisted entries that allow only some record types
+    pub typed_allowed: HashMap<&'a str, (QTypes, usize)>,
+}
+
+impl<'a> Index<'a> {
+    pub fn new(blocked: DomainSet<'a, usize>) -> Index<'a> {
+        Index {
+            blocked,
+            whitelisted: HashMap::default(),
+            typed_blocked: HashMap::default(),
+            typed_allowed: HashMap::default(),
+        }
+    }
+
+    /// Returns the entry blocking the domain, either the domain itself
+    /// or one of its parents, and the source it came from
+    /// Entries for some record types only apply to the name itself, as in the unbound
+    /// output, and only if the query type is known
+    pub fn find_blocking(&self, domain: &str, qtype: Option<&str>) -> Option<(&'a str, usize)> {
+        let blocking = self
+            .blocked
+            .find(domain)
+            .map(|(entry, source)| (entry, *source));
+        match (blocking, qtype) {
+            (None, Some(qtype)) => self
+                .typed_blocked
+                .get_key_value(domain.strip_suffix('.').unwrap_or(domain))
+                .filter(|(_, (types, _))| types.contains(qtype))
+                .map(|(entry, (_, source))| (*entry, *source)),
+            _ => blocking,
+        }
+    }
+
+    /// Returns the whitelisted entry, the domain or one of its parents,
+    /// that allows the record type even if the domain is blocked, and the source it came from
+    pub fn find_allowed_type(&self, domain: &str, qtype: &str) -> Option<(&'a str, usize)> {
+        if self.typed_allowed.is_empty() {
+            return None;
+        }
+        std::iter::once(domain)
+            .chain(sub_domain_iterator(domain, 1))
+            .find_map(|seg| {
+                self.typed_allowed
+                    .get_key_value(seg)
+                    .filter(|(_, (types, _))| types.contains(qtype))
+            })
+            .map(|(entry, (_, source))| (*entry, *source))
+    }
+
+    /// Returns the whitelisted entry, the domain or one of its parents,
+    /// that would have blocked the domain, and the source it came from
+    pub fn find_whitelisting(&self, domain: &str) -> Option<(&'a str, usize)> {
+        std::iter::once(domain)
+            .chain(sub_domain_iterator(domain, 1))
+            .find_map(|seg| self.whitelisted.get_key_value(seg))
+            .map(|(entry, source)| (*entry, *source))
+    }
+}
+
+#[cfg(test)]
+mod tests_index {
+    use crate::qtype::QTypes;
+
+    #[test]
+    fn shard_test() {
+        let fb = super::shard("fb.com", 16);
+        assert_eq!(fb, super::shard("x.ads.fb.com", 16));
+        assert_eq!(fb, super::shard("ads.fb.com.", 16));
+        assert!((0..100).all(|i| super::shard(&format!("d{i}.com"), 7) < 7));
+        assert_eq!(0, super::shard("fb.com", 1));
+    }
+
+    #[test]
+    fn find_test() {
+        let mut index = super::Index::new([("ads.fb.com", 1)].into_iter().collect());
+        index.whitelisted.insert("good.fb.com", 0);
+
+        index
+            .typed_blocked
+            .insert("fb.com", (QTypes::parse("HTTPS").unwrap(), 2));
+        index
+            .typed_allowed
+            .insert("_acme.ads.fb.com", (QTypes::parse("TXT").unwrap(), 3));
+
+        assert_eq!(
+            Some(("ads.fb.com", 1)),
+            index.find_blocking("ads.fb.com", None)
+        );
+        assert_eq!(
+            Some(("ads.fb.com", 1)),
+            index.find_blocking("x.ads.fb.com", Some("HTTPS"))
+        );
+        assert_eq!(None, index.find_blocking("fb.com", None));
+        assert_eq!(None, index.find_blocking("www.fb.com", Some("A")));
+        // as the typetransparent zone of the unbound output, only the name itself
+        assert_eq!(
+            Some(("fb.com", 2)),
+            index.find_blocking("fb.com.", Some("HTTPS"))
+        );
+        assert_eq!(None, index.find_blocking("www.fb.com", Some("HTTPS")));
+        assert_eq!(
+            Some(("_acme.ads.fb.com", 3)),
+            index.find_allowed_type("_acme.ads.fb.com", "TXT")
+        );
+        assert_eq!(None, index.find_allowed_type("_acme.ads.fb.com", "A"));
+        assert_eq!(
+            Some(("good.fb.com", 0)),
+            index.find_whitelisting("www.good.fb.com")
+        );
+        assert_eq!(None, index.find_whitelisting("ads.fb.com"));
+    }
+}
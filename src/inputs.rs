@@ -0,0 +1,119 @@
+//! Expanding the list arguments into the files to read
+//!
+//! A list can be given as a file, as a directory whose files are all read, as a glob
+//! like `lists.d/*.list` or as `-` for the standard input. Directories and globs are
+//! read in the order of the file names, so the same files always build the same list.
+
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The argument standing for the standard input
+pub const STDIN: &str = "-";
+
+/// True if the argument is a glob rather than a file name
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// The name a list is known by in the logs, rejects and statistics
+pub fn name(path: &str) -> &str {
+    if path == STDIN {
+        "stdin"
+    } else {
+        path
+    }
+}
+
+/// The files an argument stands for, hidden files are left out as the shell does
+pub fn expand(path: &str) -> Result<Vec<String>> {
+    if path == STDIN || !(is_pattern(path) || Path::new(path).is_dir()) {
+        return Ok(vec![path.to_string()]);
+    }
+    let mut files: Vec<String> = if is_pattern(path) {
+        let invalid = |e: glob::PatternError| {
+            Error::input(path, io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let options = glob::MatchOptions {
+            require_literal_leading_dot: true,
+            ..Default::default()
+        };
+        glob::glob_with(path, options)
+            .map_err(invalid)?
+            .filter_map(|entry| entry.ok())
+            .filter(|p| p.is_file())
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect()
+    } else {
+        fs::read_dir(path)
+            .map_err(|e| Error::input(path, e))?
+            .filter_map(|entry| entry.ok())
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect()
+    };
+    if files.is_empty() {
+        return Err(Error::input(
+            path,
+            io::Error::new(io::ErrorKind::NotFound, "no list files found"),
+        ));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Expands all the arguments, the standard input can only be read once
+pub fn expand_all<'a>(paths: impl IntoIterator<Item = &'a String>) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for path in paths {
+        files.extend(expand(path)?);
+    }
+    if files.iter().filter(|f| *f == STDIN).count() > 1 {
+        return Err(Error::InvalidInput(
+            "the standard input can only be given once as a list".to_string(),
+        ));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests_inputs {
+    use super::*;
+
+    #[test]
+    fn expand_test() {
+        let dir = std::env::temp_dir().join(format!("dns-block-inputs-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lists.d")).unwrap();
+        for f in ["b.list", "a.list", "c.txt", ".hidden.list"] {
+            fs::write(dir.join("lists.d").join(f), "ads.fb.com\n").unwrap();
+        }
+        let lists = dir.join("lists.d");
+        let lists = lists.to_str().unwrap();
+
+        let glob = expand(&format!("{lists}/*.list")).unwrap();
+        assert_eq!(
+            vec![format!("{lists}/a.list"), format!("{lists}/b.list")],
+            glob
+        );
+        let all = expand(lists).unwrap();
+        assert_eq!(
+            vec![
+                format!("{lists}/a.list"),
+                format!("{lists}/b.list"),
+                format!("{lists}/c.txt")
+            ],
+            all
+        );
+        assert_eq!(vec!["-"], expand("-").unwrap());
+        assert!(matches!(
+            expand(&format!("{lists}/*.gz")),
+            Err(Error::Input { .. })
+        ));
+        let twice = ["-".to_string(), "-".to_string()];
+        assert!(matches!(expand_all(&twice), Err(Error::InvalidInput(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
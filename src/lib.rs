@@ -0,0 +1,36 @@
+//! Simplifies lists of ad and tracking servers to block
+//!
+//! A [`BlockListBuilder`] collects block lists and whitelists in hosts or plain format and
+//! builds a [`BlockList`]. Subdomains of blocked domains are folded into their parents
+//! and whitelisted domains are kept out. The block list answers queries and writes
+//! itself in the formats understood by DNS servers, see [`Format`].
+
+pub mod audit;
+pub mod blocklist;
+pub mod client_filter;
+pub mod compression;
+pub mod config;
+mod dns_resolver;
+pub mod domain_set;
+pub mod error;
+pub mod filter;
+pub mod guard;
+pub mod index;
+pub mod inputs;
+pub mod low_memory;
+pub mod output;
+pub mod packed;
+pub mod prefilter;
+pub mod public_suffix;
+pub mod qtype;
+pub mod state;
+pub mod statistics;
+pub mod sub_domains;
+pub mod summary;
+pub mod writers;
+
+pub use blocklist::{BlockList, BlockListBuilder};
+pub use compression::Compression;
+pub use error::{Error, Result};
+pub use filter::QueryResult;
+pub use writers::{Format, Order};
@@ -0,0 +1,912 @@
+//! Building the outputs of lists larger than the memory of small devices
+//!
+//! The lists are read a line at a time and their domains sorted by the labels from the
+//! top level domain down, in runs that fit the memory limit and are merged from disk.
+//! The names of a run are kept as ids of their labels, most labels repeat. In that
+//! order a domain comes right after its parents, so the folding only has to remember
+//! the chain of blocked parents of the current name. The entries go to files in the
+//! same order and the writers read them from there.
+//!
+//! The outputs and the statistics are the same as the ones of a block list built in
+//! memory, unsorted outputs come out in the reversed order. The files go to the
+//! temporary directory, set TMPDIR to a directory on disk if /tmp is kept in memory.
+//!
+//! The limit covers what the build keeps, not the whitelists, which are read whole,
+//! or the counts of folded subdomains by registrable domain. Where transparent huge
+//! pages are enabled the allocator can keep more resident than it hands out, run with
+//! MIMALLOC_ARENA_EAGER_COMMIT=0 to avoid that.
+
+use crate::blocklist::{log_statistics, parse_line, BlockListBuilder, SOURCE_HEADER};
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use crate::guard::Checked;
+use crate::inputs;
+use crate::output;
+use crate::packed;
+use crate::qtype::QTypes;
+use crate::statistics::Statistics;
+use crate::sub_domains::{cmp_labels, labels, normalize_line, sub_domain_iterator};
+use crate::writers::{self, Entries, Format, Order, Table, Visit};
+use fnv::FnvHashMap as HashMap;
+use log::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
+
+/// The smallest memory limit in megabytes, below it the buffers don't fit
+pub const MIN_MEMORY_LIMIT: u64 = 16;
+/// The runs are sorted in this part of the memory limit, the rest is for the whitelist,
+/// the buffers of the merge and the packed output, which is put together in memory
+const RUN_SHARE: usize = 2;
+/// Size of the buffer of each file read or written
+const BUFFER: usize = 64 * 1024;
+/// Most runs merged at once, more are merged in several passes
+const MAX_FAN_IN: usize = 64;
+/// Rough cost of a label in the map of ids and in the list besides its bytes, kept twice
+const LABEL_OVERHEAD: usize = 80;
+
+/// A name and where it came from, a domain read from a list or an entry of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    /// position in the input, of the records for the same name the first one counts
+    seq: u64,
+    source: u32,
+    /// 0 for a domain blocked for all record types
+    qtypes: u32,
+}
+
+impl Record {
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&(self.name.len() as u32).to_le_bytes())?;
+        w.write_all(self.name.as_bytes())?;
+        w.write_all(&self.seq.to_le_bytes())?;
+        w.write_all(&self.source.to_le_bytes())?;
+        w.write_all(&self.qtypes.to_le_bytes())
+    }
+
+    /// None at the end of the file
+    fn read(r: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut len = [0; 4];
+        match r.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut name = vec![0; u32::from_le_bytes(len) as usize];
+        r.read_exact(&mut name)?;
+        let mut fields = [0; 16];
+        r.read_exact(&mut fields)?;
+        let field = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap());
+        Ok(Some(Record {
+            name: String::from_utf8(name)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            seq: u64::from_le_bytes(fields[..8].try_into().unwrap()),
+            source: field(8),
+            qtypes: field(12),
+        }))
+    }
+}
+
+/// The order records are sorted in, unsorted ones are sorted by labels
+fn compare(order: Order, a: &Record, b: &Record) -> Ordering {
+    match order {
+        Order::Alphabetical => a.name.cmp(&b.name),
+        Order::Reversed | Order::Unsorted => cmp_labels(&a.name, &b.name),
+    }
+    .then(a.seq.cmp(&b.seq))
+}
+
+/// Checks if the name is below the parent, by whole labels
+fn is_subdomain(name: &str, parent: &str) -> bool {
+    let mut labels_of_name = labels(name);
+    labels(parent).all(|label| labels_of_name.next() == Some(label))
+        && labels_of_name.next().is_some()
+}
+
+/// The directory of the runs and tables of one build, removed with it
+struct TempDir {
+    path: PathBuf,
+    next: AtomicUsize,
+}
+
+impl TempDir {
+    fn new() -> Result<TempDir> {
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let build = BUILDS.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = std::env::temp_dir().join(format!("dns-block-{}-{}", std::process::id(), build));
+        fs::create_dir_all(&path).map_err(|e| Error::output(path.to_string_lossy(), e))?;
+        Ok(TempDir {
+            path,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// A new file name, the writers sort in parallel
+    fn file(&self) -> PathBuf {
+        let n = self.next.fetch_add(1, AtomicOrdering::Relaxed);
+        self.path.join(format!("{}.run", n))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Reads the records of a run or a table
+struct Run {
+    reader: BufReader<File>,
+}
+
+impl Run {
+    fn open(path: &PathBuf) -> io::Result<Run> {
+        Ok(Run {
+            reader: BufReader::with_capacity(BUFFER, File::open(path)?),
+        })
+    }
+}
+
+impl Iterator for Run {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        Record::read(&mut self.reader).transpose()
+    }
+}
+
+/// A record of the run being filled, its name as ids of the labels from the top level domain down
+struct Interned {
+    start: u32,
+    len: u32,
+    trailing_dot: bool,
+    seq: u64,
+    source: u32,
+    qtypes: u32,
+}
+
+/// Sorts more records than fit in memory: runs that fit in the budget are sorted
+/// and written to files, the files are merged at the end
+struct Sorter<'t> {
+    temp: &'t TempDir,
+    order: Order,
+    budget: usize,
+    ids: HashMap<Box<str>, u32>,
+    labels: Vec<Box<str>>,
+    label_bytes: usize,
+    names: Vec<u32>,
+    records: Vec<Interned>,
+    runs: Vec<PathBuf>,
+}
+
+impl<'t> Sorter<'t> {
+    /// The records take half of the budget, the ids of the labels of their names
+    /// a quarter and the labels the rest
+    fn new(temp: &'t TempDir, order: Order, budget: usize) -> Sorter<'t> {
+        Sorter {
+            temp,
+            order,
+            budget,
+            ids: HashMap::default(),
+            labels: Vec::new(),
+            label_bytes: 0,
+            names: Vec::with_capacity(budget / 4 / size_of::<u32>()),
+            records: Vec::with_capacity(budget / 2 / size_of::<Interned>()),
+            runs: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, name: &str, seq: u64, source: u32, qtypes: u32) -> io::Result<()> {
+        // full runs are written before they grow past their share
+        if self.records.len() == self.records.capacity()
+            || self.names.len() + labels(name).count() > self.names.capacity()
+        {
+            self.write_run()?;
+        }
+        let start = self.names.len();
+        for label in labels(name) {
+            let id = match self.ids.get(label) {
+                Some(id) => *id,
+                None => {
+                    let id = self.labels.len() as u32;
+                    self.labels.push(label.into());
+                    self.ids.insert(label.into(), id);
+                    self.label_bytes += 2 * label.len() + LABEL_OVERHEAD;
+                    id
+                }
+            };
+            self.names.push(id);
+        }
+        self.records.push(Interned {
+            start: start as u32,
+            len: (self.names.len() - start) as u32,
+            trailing_dot: name.ends_with('.'),
+            seq,
+            source,
+            qtypes,
+        });
+        if self.label_bytes > self.budget / 4 {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn labels_of(&self, record: &Interned) -> impl DoubleEndedIterator<Item = &str> {
+        let ids = &self.names[record.start as usize..(record.start + record.len) as usize];
+        ids.iter().map(|id| &*self.labels[*id as usize])
+    }
+
+    /// The bytes of the name, without putting it together
+    fn bytes<'s>(&'s self, record: &'s Interned) -> impl Iterator<Item = u8> + 's {
+        self.labels_of(record)
+            .rev()
+            .enumerate()
+            .flat_map(|(i, label)| (i > 0).then_some(b'.').into_iter().chain(label.bytes()))
+            .chain(record.trailing_dot.then_some(b'.'))
+    }
+
+    fn name(&self, record: &Interned) -> String {
+        let bytes = self.bytes(record).collect();
+        String::from_utf8(bytes).expect("labels of a valid name")
+    }
+
+    fn compare(&self, a: &Interned, b: &Interned) -> Ordering {
+        match self.order {
+            Order::Alphabetical => self.bytes(a).cmp(self.bytes(b)),
+            Order::Reversed | Order::Unsorted => self.labels_of(a).cmp(self.labels_of(b)),
+        }
+        .then(a.seq.cmp(&b.seq))
+    }
+
+    /// Sorts the records in memory and writes them to a new run
+    fn write_run(&mut self) -> io::Result<()> {
+        let mut records = std::mem::take(&mut self.records);
+        records.sort_unstable_by(|a, b| self.compare(a, b));
+        let path = self.temp.file();
+        let mut w = BufWriter::with_capacity(BUFFER, File::create(&path)?);
+        for r in &records {
+            let record = Record {
+                name: self.name(r),
+                seq: r.seq,
+                source: r.source,
+                qtypes: r.qtypes,
+            };
+            record.write(&mut w)?;
+        }
+        w.flush()?;
+        self.runs.push(path);
+        // the next run reuses the memory
+        records.clear();
+        self.records = records;
+        self.ids.clear();
+        self.labels.clear();
+        self.label_bytes = 0;
+        self.names.clear();
+        Ok(())
+    }
+
+    /// The records in order, merged from the runs
+    fn finish(mut self) -> io::Result<Merge> {
+        if !self.records.is_empty() || self.runs.is_empty() {
+            self.write_run()?;
+        }
+        // the merge gets the memory of the runs
+        let runs = std::mem::take(&mut self.runs);
+        let (temp, order, budget) = (self.temp, self.order, self.budget);
+        drop(self);
+        merge(temp, runs, order, budget)
+    }
+}
+
+/// Merges the runs, in several passes if there are too many to read at once
+fn merge(temp: &TempDir, mut runs: Vec<PathBuf>, order: Order, budget: usize) -> io::Result<Merge> {
+    let fan_in = (budget / BUFFER).clamp(2, MAX_FAN_IN);
+    while runs.len() > fan_in {
+        let merged: Vec<PathBuf> = runs.drain(..fan_in).collect();
+        let path = temp.file();
+        let mut w = BufWriter::with_capacity(BUFFER, File::create(&path)?);
+        for record in Merge::new(merged, order)? {
+            record?.write(&mut w)?;
+        }
+        w.flush()?;
+        runs.push(path);
+    }
+    Merge::new(runs, order)
+}
+
+/// The next record of a run, the smallest one comes out first of the heap
+struct Head {
+    record: Record,
+    run: usize,
+    order: Order,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        compare(self.order, &self.record, &other.record)
+    }
+}
+
+/// Merges sorted runs, deleting them once read
+struct Merge {
+    runs: Vec<Run>,
+    paths: Vec<PathBuf>,
+    heap: BinaryHeap<Reverse<Head>>,
+    order: Order,
+}
+
+impl Merge {
+    fn new(paths: Vec<PathBuf>, order: Order) -> io::Result<Merge> {
+        let mut merge = Merge {
+            runs: paths.iter().map(Run::open).collect::<io::Result<_>>()?,
+            paths,
+            heap: BinaryHeap::new(),
+            order,
+        };
+        for run in 0..merge.runs.len() {
+            merge.advance(run)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        if let Some(record) = self.runs[run].next().transpose()? {
+            self.heap.push(Reverse(Head {
+                record,
+                run,
+                order: self.order,
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        let Reverse(head) = self.heap.pop()?;
+        Some(self.advance(head.run).map(|_| head.record))
+    }
+}
+
+impl Drop for Merge {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The entries of a table, written in the order of the labels
+struct Spool {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    count: usize,
+}
+
+impl Spool {
+    fn new(temp: &TempDir) -> io::Result<Spool> {
+        let path = temp.file();
+        let writer = BufWriter::with_capacity(BUFFER, File::create(&path)?);
+        Ok(Spool {
+            path,
+            writer: Some(writer),
+            count: 0,
+        })
+    }
+
+    fn add(&mut self, name: &str, source: u32, qtypes: u32) -> io::Result<()> {
+        let record = Record {
+            name: name.to_string(),
+            seq: self.count as u64,
+            source,
+            qtypes,
+        };
+        self.count += 1;
+        record.write(
+            self.writer
+                .as_mut()
+                .expect("the table is still being written"),
+        )
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A block list folded on disk, in the low memory mode
+pub struct LowMemoryList {
+    tables: [Spool; 4],
+    sources: Vec<String>,
+    /// entries refused for being public suffixes, with their source
+    public_suffixes: Vec<(String, usize)>,
+    rejected: Vec<String>,
+    statistics: Statistics<'static>,
+    budget: usize,
+    order: Order,
+    // dropped last, it has the files of the tables
+    temp: TempDir,
+}
+
+/// What the lists were read into
+struct Input<'t> {
+    sorter: Sorter<'t>,
+    sources: Vec<String>,
+    public_suffixes: Vec<(String, usize)>,
+    rejected: Vec<String>,
+    seq: u64,
+}
+
+impl LowMemoryList {
+    /// Reads the block list files a line at a time and folds them on disk, the
+    /// builder has the whitelists and the settings. The memory limit is in bytes
+    pub fn build(
+        builder: &BlockListBuilder,
+        block_files: &[String],
+        memory_limit: usize,
+    ) -> Result<LowMemoryList> {
+        let temp = TempDir::new()?;
+        let temp_error = |e| Error::output(temp.path.to_string_lossy(), e);
+        let budget = memory_limit / RUN_SHARE;
+        let mut input = Input {
+            sorter: Sorter::new(&temp, Order::Reversed, budget),
+            sources: builder.whitelist_names().map(str::to_string).collect(),
+            public_suffixes: Vec::new(),
+            rejected: Vec::new(),
+            seq: 0,
+        };
+
+        let cnames = thread::scope(|scope| {
+            debug!("Do the DNS requests for whitelisted domains while we read the domains we want to block");
+            let resolver = scope.spawn(|| builder.expand_whitelist());
+            for file in block_files {
+                read_list(builder, file, &mut input)?;
+            }
+            resolver
+                .join()
+                .map_err(|_| io::Error::other("the DNS resolver thread panicked"))
+                .and_then(|r| r)
+                .map_err(Error::Resolver)
+        })?;
+        let Input {
+            sorter,
+            sources,
+            public_suffixes,
+            mut rejected,
+            ..
+        } = input;
+
+        let mut whitelist_rejected = Vec::new();
+        let (whitelist, typed_whitelist) = builder.whitelist(&cnames, &mut whitelist_rejected);
+        rejected.extend(whitelist_rejected.iter().map(|r| r.to_string()));
+        for rejection in &rejected {
+            warn!("Rejected {}", rejection);
+        }
+
+        let mut tables = [
+            Spool::new(&temp).map_err(temp_error)?,
+            Spool::new(&temp).map_err(temp_error)?,
+            Spool::new(&temp).map_err(temp_error)?,
+            Spool::new(&temp).map_err(temp_error)?,
+        ];
+        let mut statistics = Statistics::default();
+        let merged = sorter.finish().map_err(temp_error)?;
+        let mut fold = Fold {
+            whitelist: &whitelist,
+            public_suffixes: builder.public_suffix_list(),
+            tables: &mut tables,
+            statistics: &mut statistics,
+            parents: Vec::new(),
+        };
+        let mut group: Vec<Record> = Vec::new();
+        for record in merged {
+            let record = record.map_err(temp_error)?;
+            if group
+                .first()
+                .is_some_and(|first| cmp_labels(&first.name, &record.name) != Ordering::Equal)
+            {
+                fold.group(&group).map_err(temp_error)?;
+                group.clear();
+            }
+            group.push(record);
+        }
+        if !group.is_empty() {
+            fold.group(&group).map_err(temp_error)?;
+        }
+
+        let mut typed_allowed: Vec<(&str, (QTypes, usize))> =
+            typed_whitelist.iter().map(|(d, t)| (*d, *t)).collect();
+        typed_allowed.sort_unstable_by(|a, b| cmp_labels(a.0, b.0));
+        for (name, (qtypes, source)) in typed_allowed {
+            tables[Table::TypedAllowed as usize]
+                .add(name, source as u32, qtypes.bits())
+                .map_err(temp_error)?;
+        }
+        for table in tables.iter_mut() {
+            table.close().map_err(temp_error)?;
+        }
+
+        statistics.add_public_suffixes(public_suffixes.len());
+        statistics.add_rejected(rejected.len());
+        log_statistics(&statistics);
+        Ok(LowMemoryList {
+            tables,
+            sources,
+            public_suffixes,
+            rejected,
+            statistics,
+            budget,
+            order: Order::default(),
+            temp,
+        })
+    }
+
+    /// Sets the order of the domains in the outputs, unsorted ones come out reversed
+    pub fn with_order(mut self, order: Order) -> LowMemoryList {
+        self.order = order;
+        self
+    }
+
+    /// Writes the entries in the given format
+    pub fn write(&self, format: Format, w: &mut impl Write) -> io::Result<()> {
+        match format {
+            Format::Packed => {
+                packed::write_in_passes(self, w)?;
+                w.flush()
+            }
+            _ => writers::write_entries(format, self.order, self, w),
+        }
+    }
+
+    /// Replaces the file with the entries in the given format and compression, keeping
+    /// the previous versions
+    pub fn write_file(
+        &self,
+        format: Format,
+        compression: Compression,
+        path: &str,
+        keep: usize,
+    ) -> Result<()> {
+        output::write_atomic(path, keep, |f| {
+            compression::compress(compression, f, |w| self.write(format, w))
+        })
+    }
+
+    /// Writes several files one after the other, sorting them takes memory
+    pub fn write_files(&self, outputs: &[(Format, Compression, &str, usize)]) -> Result<()> {
+        for (format, compression, path, keep) in outputs {
+            self.write_file(*format, *compression, path, *keep)?;
+            info!("Wrote {} in {} format", path, format);
+        }
+        Ok(())
+    }
+
+    /// Writes the input lines that are not valid domain names, one per line with
+    /// the file and line number they came from
+    pub fn write_rejects(&self, path: &str) -> Result<()> {
+        output::write_atomic(path, 0, |f| {
+            for rejection in &self.rejected {
+                writeln!(f, "{}", rejection)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Number of blocked entries, not counting the subdomains they cover
+    pub fn len(&self) -> usize {
+        self.tables[Table::Blocked as usize].count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn statistics(&self) -> &Statistics<'static> {
+        &self.statistics
+    }
+}
+
+/// Reads a block list a line at a time, the same way the builder reads a whole one
+fn read_list(builder: &BlockListBuilder, file: &str, input: &mut Input) -> Result<()> {
+    let input_error = |e| Error::input(inputs::name(file), e);
+    let mut reader = compression::open(file)?;
+    let mut source = input.sources.len();
+    input.sources.push(inputs::name(file).to_string());
+
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf).map_err(input_error)? == 0 {
+            return Ok(());
+        }
+        line_number += 1;
+        if buf.ends_with(b"\n") {
+            buf.pop();
+            if buf.ends_with(b"\r") {
+                buf.pop();
+            }
+        }
+        let line = std::str::from_utf8(&buf)
+            .map_err(|e| input_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        let line = normalize_line(line).to_ascii_lowercase();
+
+        if let Some(url) = line.strip_prefix(SOURCE_HEADER) {
+            source = input.sources.len();
+            input.sources.push(url.trim().to_string());
+            continue;
+        }
+        let mut rejected = Vec::new();
+        match parse_line(file, line_number, &line, &mut rejected) {
+            Some(domain) if builder.public_suffix_list().is_public_suffix(domain.name) => {
+                warn!(
+                    "Refusing to block the public suffix {} from {}",
+                    domain.name, input.sources[source]
+                );
+                input
+                    .public_suffixes
+                    .push((domain.name.to_string(), source));
+            }
+            Some(domain) => {
+                let qtypes = domain.qtypes.map(|q| q.bits()).unwrap_or(0);
+                input
+                    .sorter
+                    .push(domain.name, input.seq, source as u32, qtypes)
+                    .map_err(|e| Error::output(input.sorter.temp.path.to_string_lossy(), e))?;
+                input.seq += 1;
+            }
+            None => input
+                .rejected
+                .extend(rejected.iter().map(|r| r.to_string())),
+        }
+    }
+}
+
+/// Folds the domains coming in the order of their labels into the tables
+struct Fold<'f, 'w> {
+    whitelist: &'f fnv::FnvHashSet<&'w str>,
+    public_suffixes: &'f crate::public_suffix::PublicSuffixList,
+    tables: &'f mut [Spool; 4],
+    statistics: &'f mut Statistics<'static>,
+    /// the names above the current one blocked for all record types, with the position
+    /// of the first record blocking them or one of their parents
+    parents: Vec<(String, u64)>,
+}
+
+impl Fold<'_, '_> {
+    /// Folds the records of one name, in the order they came in, the same way the
+    /// index folds them one at a time in that order
+    fn group(&mut self, group: &[Record]) -> io::Result<()> {
+        let name = &group[0].name;
+        while let Some((parent, _)) = self.parents.last() {
+            if is_subdomain(name, parent) {
+                break;
+            }
+            self.parents.pop();
+        }
+        // the names don't outlive the group, their registrable domain is only looked up
+        // for the ones folded
+        let public_suffixes = self.public_suffixes;
+        let registrable = || public_suffixes.registrable_domain(name).unwrap_or(name);
+        // from this position on the parents cover the name
+        let covered_from = self.parents.last().map(|(_, seq)| *seq);
+
+        // the last source of a whitelisted name counts, as in the index
+        let mut whitelisted: Vec<(&str, u32)> = Vec::new();
+        let mut blocked: Option<&Record> = None;
+        // position of the first record blocking the name itself for all types
+        let mut blocked_from: Option<u64> = None;
+        let mut typed: Vec<(&Record, u32)> = Vec::new();
+        for record in group {
+            if self.whitelist.contains(record.name.as_str()) {
+                match whitelisted
+                    .iter_mut()
+                    .find(|(name, _)| *name == record.name)
+                {
+                    Some((_, source)) => *source = record.source,
+                    None => {
+                        whitelisted.push((&record.name, record.source));
+                        self.statistics.increment_distinct_whitelisted();
+                    }
+                }
+                debug!("Whitelisted {}", record.name);
+                self.statistics.increment_whitelisted();
+            } else if record.qtypes == 0 {
+                // blocked entries fold their subdomains whatever order they come in
+                blocked_from = blocked_from.or(Some(record.seq));
+                if covered_from.is_some() {
+                    self.statistics.increment_parent_copied(registrable());
+                } else if blocked.is_some() {
+                    self.statistics.increment_duplicate();
+                } else {
+                    blocked = Some(record);
+                    self.statistics.increment_blocked();
+                }
+            } else {
+                // an entry for some types counts as covered only if what covers it came first
+                // the records come in order, a blocked record of the name came before
+                if blocked_from.is_some() || covered_from.is_some_and(|seq| seq < record.seq) {
+                    self.statistics.increment_parent_copied(registrable());
+                } else if let Some((_, qtypes)) =
+                    typed.iter_mut().find(|(t, _)| t.name == record.name)
+                {
+                    *qtypes |= record.qtypes;
+                    self.statistics.increment_duplicate();
+                } else {
+                    typed.push((record, record.qtypes));
+                    self.statistics.increment_blocked();
+                }
+            }
+        }
+
+        for (name, source) in whitelisted {
+            self.tables[Table::Whitelisted as usize].add(name, source, 0)?;
+        }
+        if let Some(first) = blocked_from {
+            let seq = covered_from.map_or(first, |seq| seq.min(first));
+            self.parents.push((name.clone(), seq));
+        }
+        match blocked {
+            Some(entry) => self.tables[Table::Blocked as usize].add(&entry.name, entry.source, 0),
+            // a blocked name or parent leaves out the entries for some types
+            None if covered_from.is_none() => typed.iter().try_for_each(|(entry, qtypes)| {
+                self.tables[Table::TypedBlocked as usize].add(&entry.name, entry.source, *qtypes)
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Entries for LowMemoryList {
+    fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    fn count(&self, table: Table) -> usize {
+        self.tables[table as usize].count
+    }
+
+    fn for_each(&self, table: Table, order: Order, visit: &mut Visit) -> io::Result<()> {
+        let entries = Run::open(&self.tables[table as usize].path)?;
+        let visit_record = |record: io::Result<Record>| {
+            let record = record?;
+            visit(
+                &record.name,
+                record.source as usize,
+                QTypes::from_bits(record.qtypes),
+            )
+        };
+        if order != Order::Alphabetical {
+            return entries.into_iter().try_for_each(visit_record);
+        }
+        let mut sorter = Sorter::new(&self.temp, order, self.budget);
+        for record in entries {
+            let record = record?;
+            sorter.push(&record.name, record.seq, record.source, record.qtypes)?;
+        }
+        sorter.finish()?.try_for_each(visit_record)
+    }
+}
+
+impl Checked for LowMemoryList {
+    fn count(&self) -> usize {
+        LowMemoryList::len(self)
+    }
+
+    fn public_suffixes(&self) -> Vec<(&str, &str)> {
+        self.public_suffixes
+            .iter()
+            .map(|(name, source)| (name.as_str(), self.sources[*source].as_str()))
+            .collect()
+    }
+
+    fn blocking(&self, domains: &[String]) -> Vec<Option<(String, String)>> {
+        // the domains and their parents, an entry equal to one of them blocks the domain
+        let mut wanted: HashMap<&str, Vec<usize>> = HashMap::default();
+        for (i, domain) in domains.iter().enumerate() {
+            for name in std::iter::once(domain.as_str()).chain(sub_domain_iterator(domain, 1)) {
+                wanted.entry(name).or_default().push(i);
+            }
+        }
+        let mut blocking = vec![None; domains.len()];
+        let found = self.for_each(Table::Blocked, Order::Reversed, &mut |entry, source, _| {
+            for i in wanted.get(entry).into_iter().flatten() {
+                blocking[*i] = Some((entry.to_string(), self.sources[source].clone()));
+            }
+            Ok(())
+        });
+        if let Err(e) = found {
+            warn!("Can't check the protected domains: {}", e);
+        }
+        blocking
+    }
+}
+
+#[cfg(test)]
+mod tests_low_memory {
+    use super::*;
+
+    #[test]
+    fn same_as_in_memory_test() {
+        let dir = std::env::temp_dir().join(format!("dns-block-low-memory-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, text: &str| {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            fs::write(&path, text).unwrap();
+            path
+        };
+        let block_files = [
+            file("personal", "Tracker.com\nBücher.de\nfb.org $type=A\n"),
+            file(
+                "public",
+                "x.ads.fb.com\r\n0.0.0.0 ads.fb.com\n# dns-block: https://example.org/list\n\
+                 fb.org $type=HTTPS\nfb.org\nwww.good.fb.com\ngood.fb.com\nco.uk\n\
+                 typed.example $type=HTTPS\nsub.typed.example $type=A\ntracker.com\n\
+                 bad..name.com\n0.0.0.0 http://ads.x.com/a\nads.fb.com\n",
+            ),
+        ];
+        let builder = || {
+            BlockListBuilder::new()
+                .whitelist_source(
+                    "whitelist",
+                    "www.good.fb.com\n_acme.ads.fb.com $type=TXT\nbad_.com\n".to_string(),
+                )
+                .shards(1)
+        };
+        let in_memory = block_files
+            .iter()
+            .fold(builder(), |b, f| b.block_file(f).unwrap());
+        let builder = builder();
+
+        for order in [Order::Alphabetical, Order::Reversed] {
+            let expected = in_memory.build().unwrap().with_order(order);
+            // a few records a run, the runs are merged in several passes
+            let low_memory = LowMemoryList::build(&builder, &block_files, 2048)
+                .unwrap()
+                .with_order(order);
+            assert_eq!(expected.len(), low_memory.len());
+            assert_eq!(
+                expected.statistics().to_string(),
+                low_memory.statistics().to_string()
+            );
+            let rejected: Vec<String> = expected.rejected().iter().map(|r| r.to_string()).collect();
+            assert_eq!(rejected, low_memory.rejected);
+            for format in [Format::Plain, Format::Unbound, Format::Packed] {
+                let (mut a, mut b) = (Vec::new(), Vec::new());
+                expected.write(format, &mut a).unwrap();
+                low_memory.write(format, &mut b).unwrap();
+                assert_eq!(a, b, "{} in {:?} order", format, order);
+            }
+            let protected = ["x.good.fb.com".to_string(), "a.ads.fb.com".to_string()];
+            assert_eq!(
+                Checked::blocking(&expected, &protected),
+                Checked::blocking(&low_memory, &protected)
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,30 +1,23 @@
-//use std::collections::HashSet;
-use fnv::FnvHashSet as HashSet;
-
-use std::fs;
-use std::io::{BufWriter, Write};
-
-use std::sync::mpsc;
-use std::thread;
-
 mod cli;
-mod dns_resolver;
-mod sub_domains;
-use sub_domains::{count_char_occurences, sub_domain_iterator, Domain};
-mod filter;
-mod statistics;
-use statistics::Statistics;
-
-use std::time::Instant;
-
-use rayon::join;
+use dns_block::client_filter::ClientFilter;
+use dns_block::config::Config;
+use dns_block::filter::{Lookup, OutputFormat};
+use dns_block::guard::{Checked, Guardrails};
+use dns_block::low_memory::LowMemoryList;
+use dns_block::packed::PackedIndex;
+use dns_block::prefilter::{self, Prefiltered};
+use dns_block::{
+    audit, compression, filter, inputs, output, state, summary, BlockList, BlockListBuilder,
+    Compression, Error, Format, Result,
+};
+
+use std::time::{Duration, Instant};
 
-use indoc::indoc;
 use log::*;
 
 use mimalloc::MiMalloc;
 
-use crate::cli::Commands;
+use crate::cli::{Cli, Commands};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -42,245 +35,367 @@ fn main() {
 
     trace!("{:#?}", command_line_params);
 
-    let start = Instant::now();
-
-    let domain_block_filename = command_line_params.domain_block_filename;
-    let whitelist_filename = command_line_params.domain_whitelist_filename;
-    let hosts_blocked_filename = command_line_params.hosts_blocked_filename;
+    if let Err(e) = run(command_line_params) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    let whitelist_string = match whitelist_filename.as_ref() {
-        "-" => String::with_capacity(0),
-        _ => fs::read_to_string(whitelist_filename).unwrap(),
-    };
+fn run(command_line_params: Cli) -> Result<()> {
+    if let Commands::Rollback { output_file } = &command_line_params.command {
+        return output::rollback(output_file);
+    }
 
-    debug!("Do the DNS requests for whitelisted domains while we read and sort the domains we want to block");
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || {
-        tx.send(expand_whitelist(whitelist_string)).unwrap();
-    });
+    let start = Instant::now();
 
-    let mut domain_block_string = fs::read_to_string(domain_block_filename).unwrap();
+    if let Some(path) = &command_line_params.index {
+        let index = PackedIndex::open(path)?;
+        info!(
+            "Mapped {} with {} entries in {} ms",
+            path,
+            index.len(),
+            start.elapsed().as_millis()
+        );
+        return query(&index, command_line_params.command);
+    }
 
-    let hosts_blocked_string = match hosts_blocked_filename.as_ref() {
-        "-" => String::with_capacity(0),
-        _ => fs::read_to_string(hosts_blocked_filename).unwrap(),
+    let config = match &command_line_params.config {
+        Some(path) => Some(Config::from_file(path)?),
+        None => None,
     };
-
-    // converting to lowercase might generate some duplicates
-    domain_block_string.make_ascii_lowercase();
-
-    // domains to blacklist should be processed from shortest
-    // to longest
-
-    let start_sorting = start.elapsed().as_millis();
-    // println!("calculate max number of lines");
-    let total = count_char_occurences(&domain_block_string, '\n')
-        + count_char_occurences(&hosts_blocked_string, '\n');
-
-    // println!("allocate a vector to fit all {} lines", total);
-    let mut bad_domains: Vec<Domain> = Vec::with_capacity(total);
-
-    // println!("put all lines from the personal block list in the vector");
-    for line in hosts_blocked_string.lines() {
-        if let Some(domain) = Domain::new(line) {
-            bad_domains.push(domain);
+    let (whitelist_files, block_files) = match &config {
+        Some(config) => (config.whitelist_files()?, config.block_files()?),
+        None => lists_from_args(&command_line_params)?,
+    };
+    let lists_from_stdin = whitelist_files
+        .iter()
+        .chain(&block_files)
+        .filter(|f| *f == inputs::STDIN)
+        .count();
+    let queries_from_stdin = match &command_line_params.command {
+        Commands::Pipe { .. } => true,
+        Commands::Audit { log_file, .. } => log_file == inputs::STDIN,
+        _ => false,
+    };
+    if lists_from_stdin + usize::from(queries_from_stdin) > 1 {
+        return Err(Error::InvalidInput(
+            "the standard input can only be read once, by one list or by the queries".to_string(),
+        ));
+    }
+    if let Commands::Pack {
+        output, compress, ..
+    } = &command_line_params.command
+    {
+        if *compress != Compression::None && output.iter().any(|(f, _)| *f == Format::Packed) {
+            return Err(Error::InvalidInput(
+                "a packed output can't be compressed, --index maps it as it is".to_string(),
+            ));
         }
     }
-
-    // println!("put all lines from the public block list in the vector");
-    for line in domain_block_string.lines() {
-        if let Some(domain) = Domain::new(line) {
-            bad_domains.push(domain);
+    let mut builder = match &config {
+        Some(config) => config.base_builder()?,
+        None => {
+            let mut builder = BlockListBuilder::new().resolve_cnames(true);
+            for f in &whitelist_files {
+                builder = builder.whitelist_file(f)?;
+            }
+            builder
         }
+    };
+    if let Some(f) = &command_line_params.public_suffix_list {
+        builder = builder.public_suffix_file(f)?;
     }
-
-    // println!("sort the vector, less dots first");
-    let start_sorting_code = start.elapsed().as_millis();
-    bad_domains.sort_unstable_by_key(|d: &Domain| d.dots);
-    let end_sorting = start.elapsed().as_millis();
-
-    // Prepare the whitelist index
-    // get the cnames from the other thread
-    let (whitelist_string, cnames) = rx.recv().unwrap();
-
-    let mut whitelist: HashSet<&str> = HashSet::default();
-
-    for line in whitelist_string.lines() {
-        process_whitelist_line(line, &mut whitelist);
+    if let Some(shards) = command_line_params.shards {
+        builder = builder.shards(shards.into());
     }
-
-    for domain in &cnames {
-        process_whitelist_line(domain, &mut whitelist);
+    if let Some(dir) = &command_line_params.state_dir {
+        builder = builder.state_dir(dir);
     }
 
-    let start_baddies = start.elapsed().as_millis();
-
-    let ((blacklist_com, statistics_com), (blacklist_net, statistics_net)) = join(
-        || process_baddies(&bad_domains, &whitelist, |s: &str| s.ends_with("com")),
-        || process_baddies(&bad_domains, &whitelist, |s: &str| !s.ends_with("com")),
-    );
-    info!("Statistics .com \n{}", &statistics_com);
-    info!("Statistics .net \n{}", &statistics_net);
-    info!(
-        "Statistics total \n{}",
-        Statistics::aggregate(&statistics_com, &statistics_net)
-    );
+    let order = command_line_params
+        .sort
+        .or(config.as_ref().map(|c| c.sort))
+        .unwrap_or_default();
+    let rejects = command_line_params
+        .rejects
+        .clone()
+        .or(config.as_ref().and_then(|c| c.rejects.clone()));
+
+    let memory_limit = command_line_params
+        .memory_limit
+        .or(config.as_ref().and_then(|c| c.memory_limit));
+    if let Some(mb) = memory_limit {
+        let memory_limit = usize::try_from(mb)
+            .ok()
+            .and_then(|mb| mb.checked_mul(1 << 20))
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "a memory limit of {} MB is more than this machine can address",
+                    mb
+                ))
+            })?;
+        if let Commands::Pipe { .. } | Commands::Audit { .. } = command_line_params.command {
+            return Err(Error::InvalidInput(
+                "the low memory mode only writes outputs, write one with pack -o packed:FILE \
+                 and answer the queries with --index FILE"
+                    .to_string(),
+            ));
+        }
+        if config.as_ref().is_some_and(|c| c.state_dir.is_some()) {
+            warn!("The state directory is not used with a memory limit");
+        }
+        let blocklist =
+            LowMemoryList::build(&builder, &block_files, memory_limit)?.with_order(order);
+        if let Some(f) = &rejects {
+            blocklist.write_rejects(f)?;
+        }
+        let end_building = start.elapsed().as_millis();
+        write_outputs(&blocklist, command_line_params.command, config)?;
+        if command_line_params.timing {
+            info!(
+                "building: {}, writing: {}",
+                end_building,
+                start.elapsed().as_millis() - end_building
+            );
+        }
+        return Ok(());
+    }
 
-    match command_line_params.command {
-        Commands::Pipe { filter } => {
-            filter::filter(&blacklist_com, &blacklist_net, filter.as_deref()).unwrap();
+    builder = builder.block_files(&block_files)?;
+    let blocklist = builder.build()?.with_order(order);
+    if command_line_params.verify_state {
+        let state_dir = command_line_params
+            .state_dir
+            .as_ref()
+            .or(config.as_ref().and_then(|c| c.state_dir.as_ref()));
+        if state_dir.is_none() {
+            return Err(Error::InvalidInput(
+                "--verify-state needs a --state-dir or a state_dir in the configuration"
+                    .to_string(),
+            ));
         }
-        Commands::Pack { bind, output_file } => {
-            let start_writing = start.elapsed().as_millis();
-            if bind {
-                write_bind_output(&blacklist_com, &blacklist_net, &output_file);
-            } else {
-                write_output(&blacklist_com, &blacklist_net, &output_file);
-            }
+        state::verify(
+            &blocklist,
+            &builder.build_from_scratch(&blocklist)?.with_order(order),
+        )?;
+        info!("The incremental build is the same as a full one");
+    }
+    if let Some(f) = &rejects {
+        blocklist.write_rejects(f)?;
+    }
+    let end_building = start.elapsed().as_millis();
 
+    match command_line_params.command {
+        command @ (Commands::Pipe { .. } | Commands::Audit { .. }) => query(&blocklist, command)?,
+        command => {
+            write_outputs(&blocklist, command, config)?;
             if command_line_params.timing {
                 info!(
-                    "sorting: {}, sorting core: {}, until after sort: {}, processing baddies: {}",
-                    end_sorting - start_sorting,
-                    end_sorting - start_sorting_code,
-                    start_baddies,
-                    start_writing - start_baddies
+                    "building: {}, writing: {}",
+                    end_building,
+                    start.elapsed().as_millis() - end_building
                 );
             }
         }
     }
+    Ok(())
 }
 
-/// Adds a non comment line to the whitelist index
-/// It adds the domain and all parent domains
-fn process_whitelist_line<'a>(line: &'a str, index: &mut HashSet<&'a str>) {
-    if let Some(domain) = Domain::new(line) {
-        for seg in sub_domain_iterator(domain.name, 1) {
-            index.insert(seg);
-        }
-        index.insert(domain.name);
-    }
+/// A block list the build and pack commands write, in memory or folded on disk
+trait Outputs: Checked {
+    fn write_files(&self, outputs: &[(Format, Compression, &str, usize)]) -> Result<()>;
 }
 
-/// adds a domain to the blocked index if it's not already blocked already or whitelisted
-fn process_bad_domain<'a>(
-    domain: &'a str,
-    index: &mut HashSet<&'a str>,
-    whitelist: &HashSet<&'a str>,
-    statistics: &mut Statistics,
-    whitelisted: &mut HashSet<&'a str>,
-) {
-    if domain.is_empty() {
-        return;
-    }
-    for seg in sub_domain_iterator(domain, 1) {
-        if index.contains(seg) {
-            statistics.increment_parent();
-            return;
-        }
-    }
-    if !whitelist.contains(domain) {
-        if index.insert(domain) {
-            statistics.increment_blocked();
-        } else {
-            statistics.increment_duplicate();
-        }
-    } else {
-        if whitelisted.insert(domain) {
-            statistics.increment_distinct_whitelisted();
-        }
-        debug!("Whitelisted {}", domain);
-        statistics.increment_whitelisted();
+impl Outputs for BlockList<'_> {
+    fn write_files(&self, outputs: &[(Format, Compression, &str, usize)]) -> Result<()> {
+        BlockList::write_files(self, outputs)
     }
 }
 
-fn write_output(index_com: &HashSet<&str>, index_net: &HashSet<&str>, output_file: &str) {
-    let mut f = BufWriter::with_capacity(8 * 1024, fs::File::create(output_file).unwrap());
-    let eol: [u8; 1] = [10];
-    for d in index_com.iter() {
-        f.write_all(d.as_bytes()).unwrap();
-        f.write_all(&eol).unwrap();
-    }
-    for d in index_net.iter() {
-        f.write_all(d.as_bytes()).unwrap();
-        f.write_all(&eol).unwrap();
+impl Outputs for LowMemoryList {
+    fn write_files(&self, outputs: &[(Format, Compression, &str, usize)]) -> Result<()> {
+        LowMemoryList::write_files(self, outputs)
     }
-    f.flush().unwrap();
 }
 
-fn write_bind_output(index_com: &HashSet<&str>, index_net: &HashSet<&str>, output_file: &str) {
-    let preamble = indoc! {"
-        $TTL 60
-        @   IN    SOA  localhost. root.localhost.  (
-                2   ; serial 
-                3H  ; refresh 
-                1H  ; retry 
-                1W  ; expiry 
-                1H) ; minimum 
-            IN    NS    localhost.
-    "};
-    let prefix = "*.";
-    let suffix = " CNAME .";
-    let mut f = BufWriter::with_capacity(8 * 1024, fs::File::create(output_file).unwrap());
-
-    f.write_all(preamble.as_bytes()).unwrap();
-
-    let eol: [u8; 1] = [10];
-    let mut serialize_index = |index: &HashSet<&str>| {
-        for d in index.iter() {
-            f.write_all(d.as_bytes()).unwrap();
-            f.write_all(suffix.as_bytes()).unwrap();
-            f.write_all(&eol).unwrap();
-
-            f.write_all(prefix.as_bytes()).unwrap();
-            f.write_all(d.as_bytes()).unwrap();
-            f.write_all(suffix.as_bytes()).unwrap();
-            f.write_all(&eol).unwrap();
+/// Writes the outputs of the build and pack commands once they all pass the checks
+fn write_outputs(
+    blocklist: &impl Outputs,
+    command: Commands,
+    config: Option<Config>,
+) -> Result<()> {
+    match command {
+        Commands::Build => {
+            let config = config.ok_or_else(|| {
+                Error::InvalidInput("the build command needs a --config file".to_string())
+            })?;
+            if config.outputs.is_empty() {
+                warn!("No outputs in the configuration file, nothing to write");
+            }
+            // nothing is replaced unless all the outputs pass the checks
+            let guardrails = config.guardrails()?;
+            for output in &config.outputs {
+                guardrails.check(blocklist, output.format, &output.path)?;
+            }
+            let outputs: Vec<(Format, Compression, &str, usize)> = config
+                .outputs
+                .iter()
+                .map(|o| (o.format, o.compress, o.path.as_str(), o.keep))
+                .collect();
+            blocklist.write_files(&outputs)
         }
-    };
-    serialize_index(index_com);
-    serialize_index(index_net);
-
-    f.flush().unwrap();
+        Commands::Pack {
+            bind,
+            unbound,
+            unicode,
+            output_file,
+            output,
+            keep,
+            compress,
+            max_change,
+            protect,
+            force,
+        } => {
+            let format = if bind {
+                Format::Bind
+            } else if unbound {
+                Format::Unbound
+            } else if unicode {
+                Format::PlainUnicode
+            } else {
+                Format::Plain
+            };
+            let mut guardrails = Guardrails {
+                max_change: Some(max_change).filter(|m| *m > 0.0),
+                force,
+                ..Default::default()
+            };
+            if let Some(protect) = protect {
+                guardrails.protect_file(&protect)?;
+            }
+            let outputs: Vec<(Format, Compression, &str, usize)> = if output.is_empty() {
+                vec![(format, compress, &output_file, keep)]
+            } else {
+                output
+                    .iter()
+                    .map(|(format, path)| (*format, compress, path.as_str(), keep))
+                    .collect()
+            };
+            // nothing is replaced unless all the outputs pass the checks
+            for (format, _, path, _) in &outputs {
+                guardrails.check(blocklist, *format, path)?;
+            }
+            blocklist.write_files(&outputs)
+        }
+        Commands::Rollback { .. } => unreachable!("handled before building the block list"),
+        Commands::Pipe { .. } | Commands::Audit { .. } => {
+            unreachable!("answered from the block list")
+        }
+    }
 }
 
-// expand the whitelisted domains with their cnames
-fn expand_whitelist(whitelist_string: String) -> (String, Vec<String>) {
-    // println!("fetch the other domains to whitelist");
-
-    let mut explicit_whitelisted_domains = Vec::with_capacity(50);
-    for line in whitelist_string.lines() {
-        if let Some(domain) = Domain::new(line) {
-            explicit_whitelisted_domains.push(domain.name);
+/// Answers the queries of the pipe and audit commands
+fn query(lookup: &impl Lookup, command: Commands) -> Result<()> {
+    match command {
+        Commands::Pipe {
+            filter,
+            groups,
+            output,
+            summary,
+            top,
+            interval,
+            prefilter,
+        } => {
+            let client_filter = ClientFilter::new(filter.as_deref(), groups.as_deref())?;
+            let interval = Duration::from_secs(interval);
+            match prefilter {
+                Some(bits) => {
+                    let start = Instant::now();
+                    let prefiltered = Prefiltered::new(lookup, bits.into());
+                    info!(
+                        "Built a prefilter of {} KB in {} ms",
+                        prefiltered.size() >> 10,
+                        start.elapsed().as_millis()
+                    );
+                    let counters = prefiltered.counters();
+                    prefilter::report_on_signal(&counters);
+                    let piped = pipe(&prefiltered, &client_filter, output, summary, top, interval);
+                    info!("{}", counters);
+                    piped?;
+                }
+                None => pipe(lookup, &client_filter, output, summary, top, interval)?,
+            }
+        }
+        Commands::Audit {
+            log_file,
+            baseline,
+            filter,
+            groups,
+            top,
+        } => {
+            let client_filter = ClientFilter::new(filter.as_deref(), groups.as_deref())?;
+            let baseline_data = match &baseline {
+                Some(f) => Some(compression::read_to_end(f)?),
+                None => None,
+            };
+            let baseline = match (&baseline, &baseline_data) {
+                (Some(f), Some(data)) => Some(audit::Baseline::parse(f, data)?),
+                _ => None,
+            };
+            let log = compression::open(&log_file)?;
+            let statistics = audit::audit(log, lookup, baseline.as_ref(), &client_filter, top)
+                .map_err(|e| Error::input(inputs::name(&log_file), e))?;
+            print!("{}", statistics);
+        }
+        _ => {
+            return Err(Error::InvalidInput(
+                "an --index only answers queries, use it with pipe or audit".to_string(),
+            ))
         }
     }
-    let mut cnames = Vec::with_capacity(50);
-    dns_resolver::resolve_domain(&explicit_whitelisted_domains, &mut cnames).unwrap();
-    debug!("Cnames to be whitelisted: {:#?}", cnames);
-    (whitelist_string, cnames)
+    Ok(())
 }
 
-/// Makes an index from a list of domains to block
-/// filter selects a subset of domains to process, e.g. .com ones
-fn process_baddies<'a>(
-    bad_domains: &'a [Domain],
-    whitelist: &HashSet<&'a str>,
-    filter_d: fn(&str) -> bool,
-) -> (HashSet<&'a str>, Statistics) {
-    let mut blacklist: HashSet<&str> =
-        HashSet::with_capacity_and_hasher(bad_domains.len() / 2, Default::default());
-    let mut whitelisted: HashSet<&str> =
-        HashSet::with_capacity_and_hasher(whitelist.len(), Default::default());
-    let mut statistics = Statistics::new();
+/// Copies the query log or summarizes it
+fn pipe(
+    lookup: &impl Lookup,
+    client_filter: &ClientFilter,
+    output: OutputFormat,
+    summary: bool,
+    top: usize,
+    interval: Duration,
+) -> Result<()> {
+    if summary {
+        summary::summarize(lookup, client_filter, top, interval)
+    } else {
+        filter::filter(lookup, client_filter, output)
+    }
+}
 
-    for domain in bad_domains.iter().filter(|d| filter_d(d.name)) {
-        process_bad_domain(
-            domain.name,
-            &mut blacklist,
-            whitelist,
-            &mut statistics,
-            &mut whitelisted,
-        );
+/// The whitelist and block list files given as arguments, with the directories and globs
+/// expanded. The - of the optional positional arguments skips them
+fn lists_from_args(command_line_params: &Cli) -> Result<(Vec<String>, Vec<String>)> {
+    let skip = |f: &&String| *f != "-";
+    let whitelists = Some(&command_line_params.domain_whitelist_filename)
+        .filter(skip)
+        .into_iter()
+        .chain(&command_line_params.whitelist);
+    // the personal lists come first so their entries are credited to them
+    let personal = Some(&command_line_params.hosts_blocked_filename)
+        .filter(skip)
+        .into_iter()
+        .chain(&command_line_params.personal);
+    let block = command_line_params
+        .domain_block_filename
+        .iter()
+        .chain(&command_line_params.block);
+    if command_line_params.domain_block_filename.is_none() && command_line_params.block.is_empty() {
+        return Err(Error::InvalidInput(
+            "the file with the domains to block is missing".to_string(),
+        ));
     }
-    (blacklist, statistics)
+    Ok((
+        inputs::expand_all(whitelists)?,
+        inputs::expand_all(personal.chain(block))?,
+    ))
 }
@@ -0,0 +1,212 @@
+//! Replacing output files atomically, so a DNS server reloading them never sees
+//! a half written file, and keeping the previous versions around for a rollback
+
+use crate::error::{Error, Result};
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the n-th previous version of the file, e.g. simple.blocked.1
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Temporary file in the same directory as the target, rename is only atomic
+/// within a file system
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Where the current file is kept while the new one replaces it
+fn previous_path(path: &Path) -> PathBuf {
+    temp_path(path).with_extension("prev")
+}
+
+/// Makes the renames in the directory durable
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Removes the versions past `keep`, left over from runs that kept more of them
+fn prune(path: &Path, keep: usize) -> io::Result<()> {
+    let mut n = keep + 1;
+    while backup_path(path, n).exists() {
+        fs::remove_file(backup_path(path, n))?;
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Keeps the current file aside before the new one replaces it, None when there is
+/// no current file or no versions are kept
+fn keep_previous(path: &Path, keep: usize) -> io::Result<Option<PathBuf>> {
+    if keep == 0 || !path.exists() {
+        return Ok(None);
+    }
+    let previous = previous_path(path);
+    let _ = fs::remove_file(&previous);
+    // a hard link keeps the current file in place until the new one replaces it
+    if fs::hard_link(path, &previous).is_err() {
+        fs::copy(path, &previous)?;
+    }
+    Ok(Some(previous))
+}
+
+/// Shifts the previous versions up by one, the oldest one falls off, and makes the
+/// replaced file version 1. Only done once the new file is in place
+fn rotate(path: &Path, previous: &Path, keep: usize) -> io::Result<()> {
+    prune(path, keep - 1)?;
+    for n in (1..keep).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, n + 1))?;
+        }
+    }
+    fs::rename(previous, backup_path(path, 1))
+}
+
+/// Writes the file through a temporary file that is synced to disk and renamed over
+/// the target with its permissions, keeping the last `keep` versions as file.1 to file.keep.
+/// With `keep` 0 no version is made and the existing ones are left alone
+pub fn write_atomic(
+    path: &str,
+    keep: usize,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> Result<()> {
+    let target = Path::new(path);
+    let temp = temp_path(target);
+    let result = (|| {
+        let file = File::create(&temp)?;
+        // the new file replaces the target, it gets the same permissions
+        if let Ok(metadata) = fs::metadata(target) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        let mut f = BufWriter::with_capacity(8 * 1024, file);
+        write(&mut f)?;
+        f.flush()?;
+        f.get_ref().sync_all()?;
+        let previous = keep_previous(target, keep)?;
+        if let Err(e) = fs::rename(&temp, target) {
+            if let Some(previous) = &previous {
+                let _ = fs::remove_file(previous);
+            }
+            return Err(e);
+        }
+        if let Some(previous) = &previous {
+            rotate(target, previous, keep)?;
+        }
+        sync_dir(target)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|e| Error::output(path, e))
+}
+
+/// Puts the previous version of the file back in place and shifts the older ones down
+pub fn rollback(path: &str) -> Result<()> {
+    let target = Path::new(path);
+    let first = backup_path(target, 1);
+    if !first.exists() {
+        return Err(Error::InvalidInput(format!(
+            "{}: no previous version to roll back to",
+            path
+        )));
+    }
+    let result = (|| {
+        fs::rename(&first, target)?;
+        let mut n = 2;
+        while backup_path(target, n).exists() {
+            fs::rename(backup_path(target, n), backup_path(target, n - 1))?;
+            n += 1;
+        }
+        sync_dir(target)
+    })();
+    result.map_err(|e| Error::output(path, e))?;
+    info!("Restored {} from {}", path, first.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests_output {
+    use super::*;
+
+    #[test]
+    fn write_and_rollback_test() {
+        let dir = std::env::temp_dir().join(format!("dns-block-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("simple.blocked");
+        let path = path.to_str().unwrap();
+
+        for version in ["one\n", "two\n", "three\n", "four\n"] {
+            write_atomic(path, 2, |f| f.write_all(version.as_bytes())).unwrap();
+        }
+        assert_eq!("four\n", fs::read_to_string(path).unwrap());
+        assert_eq!("three\n", fs::read_to_string(format!("{path}.1")).unwrap());
+        assert_eq!("two\n", fs::read_to_string(format!("{path}.2")).unwrap());
+        assert!(!Path::new(&format!("{path}.3")).exists());
+
+        // a failing write leaves everything as it was
+        let failed = write_atomic(path, 2, |_| Err(io::Error::other("disk full")));
+        assert!(failed.is_err());
+        assert_eq!("four\n", fs::read_to_string(path).unwrap());
+        assert_eq!("two\n", fs::read_to_string(format!("{path}.2")).unwrap());
+
+        rollback(path).unwrap();
+        assert_eq!("three\n", fs::read_to_string(path).unwrap());
+        assert_eq!("two\n", fs::read_to_string(format!("{path}.1")).unwrap());
+        rollback(path).unwrap();
+        assert_eq!("two\n", fs::read_to_string(path).unwrap());
+        assert!(rollback(path).is_err());
+
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_and_prune_test() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("dns-block-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("simple.blocked");
+        let path = path.to_str().unwrap();
+
+        for version in ["one\n", "two\n", "three\n", "four\n"] {
+            write_atomic(path, 3, |f| f.write_all(version.as_bytes())).unwrap();
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(0o640)).unwrap();
+        // keeping fewer versions than the last run drops the older ones
+        write_atomic(path, 1, |f| f.write_all(b"five\n")).unwrap();
+        assert_eq!(
+            0o640,
+            fs::metadata(path).unwrap().permissions().mode() & 0o777
+        );
+        assert_eq!("four\n", fs::read_to_string(format!("{path}.1")).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+        // keeping none makes no version and leaves the existing ones alone
+        write_atomic(path, 0, |f| f.write_all(b"six\n")).unwrap();
+        assert_eq!("six\n", fs::read_to_string(path).unwrap());
+        assert_eq!("four\n", fs::read_to_string(format!("{path}.1")).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! A compact binary form of the block list that pipe and audit map into memory
+//! and use right away, without reading and folding the lists again
+//!
+//! All numbers are little endian u32. The header has the magic, the format version,
+//! the number of records in each table and a CRC32 of the rest of the file. The tables
+//! follow: the sources, then the blocked, whitelisted, typed blocked and typed allowed
+//! entries, each sorted by the labels from the top level domain down. The names the
+//! records point into come last.
+
+use crate::compression::Compression;
+use crate::error::{Error, Result};
+use crate::filter::{Lookup, QueryResult};
+use crate::qtype::QTypes;
+use crate::sub_domains::{cmp_labels, labels, normalize_name, sub_domain_iterator};
+use crate::writers::{Entries, Order, Table};
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Write};
+
+const MAGIC: &[u8; 8] = b"DNSBLOCK";
+/// Changes whenever the layout does, older files have to be packed again
+pub const VERSION: u32 = 1;
+const CHECKSUM: usize = 32;
+const HEADER: usize = 36;
+
+/// The tables, in the order they are in the file
+const SOURCES: usize = 0;
+const BLOCKED: usize = 1;
+const WHITELISTED: usize = 2;
+const TYPED_BLOCKED: usize = 3;
+const TYPED_ALLOWED: usize = 4;
+const TABLES: usize = 5;
+/// Every record starts with the offset and the length of its name, entries
+/// add their source and typed entries their record types
+const RECORD_SIZE: [usize; TABLES] = [8, 12, 12, 16, 16];
+
+/// The tables of entries, in the order they are in the file
+const ENTRY_TABLES: [(usize, Table); 4] = [
+    (BLOCKED, Table::Blocked),
+    (WHITELISTED, Table::Whitelisted),
+    (TYPED_BLOCKED, Table::TypedBlocked),
+    (TYPED_ALLOWED, Table::TypedAllowed),
+];
+
+/// Writes the entries and the names of the lists they came from
+pub fn write(entries: &dyn Entries, w: &mut impl Write) -> io::Result<()> {
+    let mut tables: [Vec<u8>; TABLES] = Default::default();
+    let mut names = Vec::new();
+
+    for source in entries.sources() {
+        add(&mut tables[SOURCES], &mut names, source, &[]);
+    }
+    for (t, table) in ENTRY_TABLES {
+        let typed = t >= TYPED_BLOCKED;
+        // sorted the way they are searched
+        entries.for_each(table, Order::Reversed, &mut |name, source, qtypes| {
+            let fields = [source as u32, qtypes.bits()];
+            let fields = if typed { &fields[..] } else { &fields[..1] };
+            add(&mut tables[t], &mut names, name, fields);
+            Ok(())
+        })?;
+    }
+
+    let counts = tables
+        .iter()
+        .enumerate()
+        .map(|(table, records)| records.len() / RECORD_SIZE[table]);
+    let mut header = header(counts);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    tables.iter().for_each(|t| hasher.update(t));
+    hasher.update(&names);
+    header.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    w.write_all(&header)?;
+    for records in &tables {
+        w.write_all(records)?;
+    }
+    w.write_all(&names)
+}
+
+/// Gets the table, the record and the name of each entry
+type VisitRecord<'v> = dyn FnMut(usize, &[u8], &str) -> io::Result<()> + 'v;
+
+/// Writes the same file as [`write`] without putting it together in memory, for
+/// entries read from disk. They are read three times: for the checksum, for the
+/// records and for the names
+pub fn write_in_passes(entries: &dyn Entries, w: &mut impl Write) -> io::Result<()> {
+    let mut sources = Vec::new();
+    let mut source_names = Vec::new();
+    for source in entries.sources() {
+        add(&mut sources, &mut source_names, source, &[]);
+    }
+    let each_record = |visit: &mut VisitRecord| {
+        let mut offset = source_names.len();
+        for (t, table) in ENTRY_TABLES {
+            let typed = t >= TYPED_BLOCKED;
+            entries.for_each(table, Order::Reversed, &mut |name, source, qtypes| {
+                let fields = [source as u32, qtypes.bits()];
+                let fields = if typed { &fields[..] } else { &fields[..1] };
+                let (record, len) = record(offset, name, fields);
+                offset += name.len();
+                visit(t, &record[..len], name)
+            })?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let mut counts = [0; TABLES];
+    counts[SOURCES] = entries.sources().len();
+    let mut hashers: [crc32fast::Hasher; TABLES] = Default::default();
+    hashers[SOURCES].update(&sources);
+    let mut names_hasher = crc32fast::Hasher::new();
+    names_hasher.update(&source_names);
+    each_record(&mut |t, record, name| {
+        counts[t] += 1;
+        hashers[t].update(record);
+        names_hasher.update(name.as_bytes());
+        Ok(())
+    })?;
+    let mut header = header(counts.into_iter());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hashers.iter().for_each(|h| hasher.combine(h));
+    hasher.combine(&names_hasher);
+    header.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    w.write_all(&header)?;
+    w.write_all(&sources)?;
+    each_record(&mut |_, record, _| w.write_all(record))?;
+    w.write_all(&source_names)?;
+    each_record(&mut |_, _, name| w.write_all(name.as_bytes()))
+}
+
+/// The header without the checksum
+fn header(counts: impl Iterator<Item = usize>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    for count in counts {
+        header.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+    header
+}
+
+fn add(table: &mut Vec<u8>, names: &mut Vec<u8>, name: &str, fields: &[u32]) {
+    let (record, len) = record(names.len(), name, fields);
+    table.extend_from_slice(&record[..len]);
+    names.extend_from_slice(name.as_bytes());
+}
+
+/// The record of a name starting at the offset in the names, and its size
+fn record(offset: usize, name: &str, fields: &[u32]) -> ([u8; 16], usize) {
+    let mut record = [0; 16];
+    record[..4].copy_from_slice(&(offset as u32).to_le_bytes());
+    record[4..8].copy_from_slice(&(name.len() as u32).to_le_bytes());
+    for (i, field) in fields.iter().enumerate() {
+        record[8 + 4 * i..12 + 4 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    (record, 8 + 4 * fields.len())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// A block list written in the packed format, by default mapped from a file
+pub struct PackedIndex<D = Mmap> {
+    data: D,
+    counts: [usize; TABLES],
+    /// where each table starts, the last one is where the names start
+    starts: [usize; TABLES + 1],
+}
+
+impl PackedIndex {
+    /// Maps the file into memory and checks it was written whole by this version
+    pub fn open(path: &str) -> Result<PackedIndex> {
+        let file = File::open(path).map_err(|e| Error::input(path, e))?;
+        // pack replaces the file by renaming a new one over it, so the mapped one never changes
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| Error::input(path, e))?;
+        let compression = Compression::detect(&map);
+        if compression != Compression::None {
+            let message = format!("compressed with {}, decompress it to map it", compression);
+            let e = io::Error::new(io::ErrorKind::InvalidData, message);
+            return Err(Error::input(path, e));
+        }
+        PackedIndex::from_bytes(map).map_err(|e| Error::input(path, e))
+    }
+}
+
+impl<D: AsRef<[u8]>> PackedIndex<D> {
+    /// Checks the header and the checksum of the data
+    pub fn from_bytes(data: D) -> io::Result<PackedIndex<D>> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let bytes = data.as_ref();
+        if bytes.len() < HEADER || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a packed dns-block index".to_string()));
+        }
+        let version = read_u32(bytes, MAGIC.len());
+        if version != VERSION {
+            return Err(invalid(format!(
+                "index version {}, this version of dns-block reads version {}, pack it again",
+                version, VERSION
+            )));
+        }
+        let mut counts = [0; TABLES];
+        let mut starts = [HEADER; TABLES + 1];
+        for table in 0..TABLES {
+            counts[table] = read_u32(bytes, MAGIC.len() + 4 + 4 * table) as usize;
+            starts[table + 1] = starts[table] + counts[table] * RECORD_SIZE[table];
+        }
+        if starts[TABLES] > bytes.len() {
+            return Err(invalid("truncated index".to_string()));
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[..CHECKSUM]);
+        hasher.update(&bytes[HEADER..]);
+        if hasher.finalize() != read_u32(bytes, CHECKSUM) {
+            return Err(invalid("wrong checksum, the index is damaged".to_string()));
+        }
+        Ok(PackedIndex {
+            data,
+            counts,
+            starts,
+        })
+    }
+
+    /// Number of entries blocked for all record types
+    pub fn len(&self) -> usize {
+        self.counts[BLOCKED]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn field(&self, table: usize, i: usize, field: usize) -> u32 {
+        read_u32(
+            self.data.as_ref(),
+            self.starts[table] + i * RECORD_SIZE[table] + 4 * field,
+        )
+    }
+
+    fn name(&self, table: usize, i: usize) -> &str {
+        if i >= self.counts[table] {
+            return "";
+        }
+        let start = self.starts[TABLES] + self.field(table, i, 0) as usize;
+        let end = start + self.field(table, i, 1) as usize;
+        self.data
+            .as_ref()
+            .get(start..end)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .unwrap_or("")
+    }
+
+    fn entry(&self, table: usize, i: usize) -> (&str, usize) {
+        (self.name(table, i), self.field(table, i, 2) as usize)
+    }
+
+    /// The first record of the table sorted after the name
+    fn upper_bound(&self, table: usize, name: &str) -> usize {
+        let (mut low, mut high) = (0, self.counts[table]);
+        while low < high {
+            let middle = (low + high) / 2;
+            if cmp_labels(self.name(table, middle), name) == Ordering::Greater {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        low
+    }
+
+    /// The record for exactly this name
+    fn get(&self, table: usize, name: &str) -> Option<usize> {
+        let i = self.upper_bound(table, name).checked_sub(1)?;
+        (cmp_labels(self.name(table, i), name) == Ordering::Equal).then_some(i)
+    }
+
+    /// The typed whitelist entry for the domain or one of its parents that has the record type
+    fn find_typed(&self, table: usize, domain: &str, qtype: &str) -> Option<(&str, usize)> {
+        if self.counts[table] == 0 {
+            return None;
+        }
+        std::iter::once(domain)
+            .chain(sub_domain_iterator(domain, 1))
+            .filter_map(|seg| self.get(table, seg))
+            .find(|i| QTypes::from_bits(self.field(table, *i, 3)).contains(qtype))
+            .map(|i| self.entry(table, i))
+    }
+
+    /// Same as [`Index::find_blocking`](crate::index::Index::find_blocking)
+    fn find_blocking(&self, domain: &str, qtype: Option<&str>) -> Option<(&str, usize)> {
+        // no entry is a parent of another one, so an entry blocking the domain
+        // is the last one sorted before it or the domain itself
+        let blocking = self
+            .upper_bound(BLOCKED, domain)
+            .checked_sub(1)
+            .map(|i| self.entry(BLOCKED, i))
+            .filter(|(entry, _)| {
+                let mut domain_labels = labels(domain);
+                labels(entry).all(|label| domain_labels.next() == Some(label))
+            });
+        match (blocking, qtype) {
+            (None, Some(qtype)) => self
+                .get(TYPED_BLOCKED, domain)
+                .filter(|i| QTypes::from_bits(self.field(TYPED_BLOCKED, *i, 3)).contains(qtype))
+                .map(|i| self.entry(TYPED_BLOCKED, i)),
+            _ => blocking,
+        }
+    }
+
+    /// Same as [`Index::find_whitelisting`](crate::index::Index::find_whitelisting)
+    fn find_whitelisting(&self, domain: &str) -> Option<(&str, usize)> {
+        std::iter::once(domain)
+            .chain(sub_domain_iterator(domain, 1))
+            .find_map(|seg| self.get(WHITELISTED, seg))
+            .map(|i| self.entry(WHITELISTED, i))
+    }
+}
+
+impl<D: AsRef<[u8]>> Lookup for PackedIndex<D> {
+    fn check(&self, domain: &str, qtype: Option<&str>) -> (QueryResult, Option<(&str, &str)>) {
+        let domain = normalize_name(domain);
+        let (result, found) =
+            if let Some(found) = qtype.and_then(|t| self.find_typed(TYPED_ALLOWED, &domain, t)) {
+                (QueryResult::Whitelisted, Some(found))
+            } else if let Some(found) = self.find_blocking(&domain, qtype) {
+                (QueryResult::Blocked, Some(found))
+            } else if let Some(found) = self.find_whitelisting(&domain) {
+                (QueryResult::Whitelisted, Some(found))
+            } else {
+                (QueryResult::Allowed, None)
+            };
+        (
+            result,
+            found.map(|(entry, source)| (entry, self.name(SOURCES, source))),
+        )
+    }
+
+    fn names(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(ENTRY_TABLES.iter().flat_map(move |(table, _)| {
+            (0..self.counts[*table]).map(move |i| self.name(*table, i))
+        }))
+    }
+
+    fn has_typed_entry(&self, domain: &str) -> bool {
+        let domain = normalize_name(domain);
+        self.get(TYPED_BLOCKED, &domain).is_some()
+            || std::iter::once(&*domain)
+                .chain(sub_domain_iterator(&domain, 1))
+                .any(|seg| self.get(TYPED_ALLOWED, seg).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests_packed {
+    use super::*;
+    use crate::{BlockListBuilder, Format};
+
+    #[test]
+    fn lookup_test() {
+        let builder = BlockListBuilder::new()
+            .whitelist_source(
+                "white",
+                "good.fb.com\n_acme.ads.fb.com $type=TXT\n".to_string(),
+            )
+            .block_source(
+                "black",
+                "ads.fb.com\nads-x.fb.com\ntracker.net\nfb.org $type=HTTPS\n".to_string(),
+            );
+        let blocklist = builder.build().unwrap();
+        let mut out = Vec::new();
+        blocklist.write(Format::Packed, &mut out).unwrap();
+
+        let packed = PackedIndex::from_bytes(&out[..]).unwrap();
+        assert_eq!(blocklist.len(), packed.len());
+        for (domain, qtype) in [
+            ("ads.fb.com", None),
+            ("x.ADS.fb.com", Some("A")),
+            ("ads-x.fb.com", None),
+            ("y.fb.com", None),
+            ("fb.com", None),
+            ("good.fb.com", None),
+            ("www.good.fb.com", None),
+            ("_acme.ads.fb.com", Some("TXT")),
+            ("_acme.ads.fb.com", Some("A")),
+            ("www.fb.org", Some("HTTPS")),
+            ("www.fb.org", Some("A")),
+            ("fb.org", Some("HTTPS")),
+            ("tracker.net.", None),
+            ("net", None),
+        ] {
+            assert_eq!(
+                blocklist.check(domain, qtype),
+                packed.check(domain, qtype),
+                "{domain}"
+            );
+        }
+        assert_eq!(
+            (QueryResult::Blocked, Some(("ads.fb.com", "black"))),
+            packed.check("x.ads.fb.com", None)
+        );
+        assert_eq!(
+            (QueryResult::Blocked, Some(("fb.org", "black"))),
+            packed.check("fb.org", Some("HTTPS"))
+        );
+        assert_eq!(
+            (QueryResult::Allowed, None),
+            packed.check("www.fb.org", Some("HTTPS"))
+        );
+
+        let mut damaged = out.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let e = PackedIndex::from_bytes(&damaged[..]).err().unwrap();
+        assert!(e.to_string().contains("checksum"));
+        let mut old = out.clone();
+        old[MAGIC.len()] = 0;
+        assert!(PackedIndex::from_bytes(&old[..]).is_err());
+        assert!(PackedIndex::from_bytes(&b"ads.fb.com\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! A Bloom filter of every name in a block list, put in front of the lookups
+//!
+//! Most queried names are not blocked, yet each of their parents is looked up in the
+//! index. The filter answers that none of the parents has an entry with a few bit probes,
+//! the lookups it can't rule out go to the index. The bits per name set the size of the
+//! filter and how many names pass it for nothing, the counters tell how it does.
+
+use crate::filter::{Lookup, QueryResult};
+use crate::sub_domains::normalize_name;
+use log::*;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+/// How often the report thread wakes up to look for a signal
+const POLL: Duration = Duration::from_millis(200);
+
+/// A set of names that can answer "maybe there" for names that are not
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// number of probes per name
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// A filter for this many names, with the number of probes that gives the fewest
+    /// false positives for the bits per name
+    pub fn new(names: usize, bits_per_name: usize) -> BloomFilter {
+        let words = (names.max(1) * bits_per_name.max(1)).div_ceil(64);
+        let hashes = (bits_per_name as f64 * std::f64::consts::LN_2).round() as u32;
+        BloomFilter {
+            bits: vec![0; words],
+            hashes: hashes.clamp(1, 16),
+        }
+    }
+
+    /// The bits to probe for a hash, a second one is derived from it and the two
+    /// combined as in Kirsch and Mitzenmacher
+    fn probes(&self, h1: u64) -> impl Iterator<Item = usize> + use<> {
+        // splitmix64 finalizer, the step between the probes, odd so they never repeat
+        let mut h2 = h1 ^ (h1 >> 30);
+        h2 = h2.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h2 ^= h2 >> 27;
+        h2 = h2.wrapping_mul(0x94d0_49bb_1331_11eb);
+        h2 ^= h2 >> 31;
+        let bits = (self.bits.len() * 64) as u128;
+        (0..u64::from(self.hashes)).map(move |i| {
+            let h = h1.wrapping_add(i.wrapping_mul(h2 | 1));
+            // maps the hash onto the bits without a division
+            ((u128::from(h) * bits) >> 64) as usize
+        })
+    }
+
+    /// Adds the name, without its final dot as the queries are looked up
+    pub fn insert(&mut self, name: &str) {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let hash = suffix_hashes(name).last().unwrap_or(FNV_OFFSET);
+        for bit in self.probes(hash) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False only if neither the name nor any of its parents was inserted
+    pub fn may_cover(&self, name: &str) -> bool {
+        suffix_hashes(name).any(|hash| {
+            self.probes(hash)
+                .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+        })
+    }
+
+    /// Size of the filter in bytes
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+/// FNV-1a of the name read from its end, the hash of each parent comes out on the way,
+/// from the top level domain down to the whole name
+fn suffix_hashes(name: &str) -> impl Iterator<Item = u64> + '_ {
+    let mut bytes = name.bytes().rev();
+    let mut hash = FNV_OFFSET;
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        for b in bytes.by_ref() {
+            let parent = hash;
+            hash = (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME);
+            if b == b'.' {
+                return Some(parent);
+            }
+        }
+        done = true;
+        Some(hash)
+    })
+}
+
+/// What the prefilter did, shared with the thread that reports it
+pub struct Counters {
+    lookups: AtomicU64,
+    /// lookups the filter answered without the index
+    rejected: AtomicU64,
+    /// lookups the filter let through that the index has no entry for
+    false_positives: AtomicU64,
+    /// when the lookups started, the rate is over the whole pipe and not only the lookups
+    started: Instant,
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters {
+            lookups: AtomicU64::default(),
+            rejected: AtomicU64::default(),
+            false_positives: AtomicU64::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        let rejected = self.rejected.load(Ordering::Relaxed);
+        let false_positives = self.false_positives.load(Ordering::Relaxed);
+        let seconds = self.started.elapsed().as_secs_f64();
+        let percent = |n: u64, of: u64| 100.0 * n as f64 / of.max(1) as f64;
+        write!(
+            f,
+            "prefilter: {} lookups, {:.0} lookups/s, {:.1}% rejected, {:.2}% false positives",
+            lookups,
+            lookups as f64 / seconds.max(1e-9),
+            percent(rejected, lookups),
+            // out of the lookups with nothing to find, the ones the filter could have rejected
+            percent(false_positives, rejected + false_positives),
+        )
+    }
+}
+
+/// Logs the counters on SIGUSR1
+pub fn report_on_signal(counters: &Arc<Counters>) {
+    let report = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&report)) {
+        warn!("Can't report the prefilter counters on SIGUSR1: {}", e);
+        return;
+    }
+    let counters = Arc::clone(counters);
+    thread::spawn(move || loop {
+        thread::sleep(POLL);
+        if report.swap(false, Ordering::Relaxed) {
+            info!("{}", counters);
+        }
+    });
+}
+
+/// A lookup that asks the Bloom filter before the index
+pub struct Prefiltered<'l, L> {
+    lookup: &'l L,
+    filter: BloomFilter,
+    counters: Arc<Counters>,
+}
+
+impl<'l, L: Lookup> Prefiltered<'l, L> {
+    /// Builds the filter from the names of the lookup
+    pub fn new(lookup: &'l L, bits_per_name: usize) -> Prefiltered<'l, L> {
+        let mut filter = BloomFilter::new(lookup.names().count(), bits_per_name);
+        for name in lookup.names() {
+            filter.insert(name);
+        }
+        Prefiltered {
+            lookup,
+            filter,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Size of the filter in bytes
+    pub fn size(&self) -> usize {
+        self.filter.size()
+    }
+
+    pub fn counters(&self) -> Arc<Counters> {
+        Arc::clone(&self.counters)
+    }
+
+    fn filtered_check(
+        &self,
+        domain: &str,
+        qtype: Option<&str>,
+    ) -> (QueryResult, Option<(&str, &str)>) {
+        if !self.filter.may_cover(&normalize_name(domain)) {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return (QueryResult::Allowed, None);
+        }
+        let checked = self.lookup.check(domain, qtype);
+        // an entry for other record types is there, the filter was right to let it through
+        if checked == (QueryResult::Allowed, None) && !self.lookup.has_typed_entry(domain) {
+            self.counters
+                .false_positives
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        checked
+    }
+}
+
+impl<L: Lookup> Lookup for Prefiltered<'_, L> {
+    fn check(&self, domain: &str, qtype: Option<&str>) -> (QueryResult, Option<(&str, &str)>) {
+        self.counters.lookups.fetch_add(1, Ordering::Relaxed);
+        self.filtered_check(domain, qtype)
+    }
+
+    fn has_typed_entry(&self, domain: &str) -> bool {
+        self.lookup.has_typed_entry(domain)
+    }
+
+    fn names(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        self.lookup.names()
+    }
+}
+
+#[cfg(test)]
+mod tests_prefilter {
+    use super::*;
+    use crate::BlockListBuilder;
+
+    #[test]
+    fn prefilter_test() {
+        let builder = BlockListBuilder::new()
+            .whitelist_source("white", "good.ads.fb.com\n".to_string())
+            .block_source(
+                "black",
+                "ads.fb.com\ntracker.net.\nfb.org $type=HTTPS\n".to_string(),
+            );
+        let blocklist = builder.build().unwrap();
+        let prefiltered = Prefiltered::new(&blocklist, 10);
+        // the whitelisted name takes its blocked parent out
+        assert_eq!(3, blocklist.names().count());
+        for (domain, qtype) in [
+            ("x.ads.fb.com", None),
+            ("www.good.ads.fb.com", Some("A")),
+            ("www.fb.org", Some("HTTPS")),
+            ("www.fb.org", Some("A")),
+            ("Tracker.net.", None),
+            ("www.tracker.net", None),
+            ("y.fb.com", None),
+        ] {
+            assert_eq!(
+                blocklist.check(domain, qtype),
+                prefiltered.check(domain, qtype),
+                "{domain}"
+            );
+        }
+        for i in 0..1000 {
+            prefiltered.check(&format!("www.d{i}.com"), None);
+        }
+        let counters = prefiltered.counters();
+        assert_eq!(1007, counters.lookups.load(Ordering::Relaxed));
+        // 10 bits per name leave about 1% false positives per probe
+        assert!(counters.rejected.load(Ordering::Relaxed) > 900);
+        assert!(counters.to_string().starts_with("prefilter: 1007 lookups"));
+        // the names blocked for HTTPS only are no false positives when queried for A
+        let false_positives = counters.false_positives.load(Ordering::Relaxed);
+        prefiltered.check("fb.org", Some("A"));
+        assert_eq!(
+            false_positives,
+            counters.false_positives.load(Ordering::Relaxed)
+        );
+
+        // names from an index written with their final dot
+        let mut filter = BloomFilter::new(1, 10);
+        filter.insert("tracker.net.");
+        assert!(filter.may_cover("www.tracker.net"));
+        assert!(filter.may_cover("tracker.net"));
+    }
+}
@@ -0,0 +1,188 @@
+//! The Public Suffix List, names under which anybody can register a domain
+//!
+//! Blocking one of them blocks a lot of unrelated sites, and the registrable
+//! domain (eTLD+1) is the unit the folding of subdomains is reported by.
+//! The list is the one published at https://publicsuffix.org/list/public_suffix_list.dat
+
+use crate::compression;
+use crate::error::Result;
+use crate::sub_domains::to_ascii;
+use fnv::FnvHashSet as HashSet;
+use fnv::FnvHasher;
+use std::hash::{Hash, Hasher};
+
+/// Used when no list is loaded, some of the most common suffixes
+const WELL_KNOWN_SUFFIXES: [&str; 32] = [
+    "co.uk",
+    "org.uk",
+    "ac.uk",
+    "gov.uk",
+    "me.uk",
+    "com.au",
+    "net.au",
+    "org.au",
+    "co.jp",
+    "ne.jp",
+    "or.jp",
+    "co.nz",
+    "com.br",
+    "com.cn",
+    "com.mx",
+    "co.in",
+    "co.za",
+    "com.tr",
+    "com.ar",
+    "co.kr",
+    "github.io",
+    "gitlab.io",
+    "blogspot.com",
+    "herokuapp.com",
+    "appspot.com",
+    "cloudfront.net",
+    "azurewebsites.net",
+    "netlify.app",
+    "pages.dev",
+    "workers.dev",
+    "vercel.app",
+    "web.app",
+];
+
+/// The rules of the list, every top level domain is a public suffix even if not listed
+#[derive(Debug, Clone)]
+pub struct PublicSuffixList {
+    rules: HashSet<String>,
+    /// *.ck, every child of these is a public suffix
+    wildcards: HashSet<String>,
+    /// !www.ck, not a public suffix despite a wildcard
+    exceptions: HashSet<String>,
+}
+
+impl Default for PublicSuffixList {
+    fn default() -> PublicSuffixList {
+        PublicSuffixList::parse(&WELL_KNOWN_SUFFIXES.join("\n"))
+    }
+}
+
+impl PublicSuffixList {
+    /// Parses the list format, one rule per line, // comments. Internationalized rules
+    /// are kept as A-labels, the form the names are looked up in
+    pub fn parse(text: &str) -> PublicSuffixList {
+        let mut list = PublicSuffixList {
+            rules: HashSet::default(),
+            wildcards: HashSet::default(),
+            exceptions: HashSet::default(),
+        };
+        for line in text.lines() {
+            let rule = match line.split_whitespace().next() {
+                Some(rule) if !rule.starts_with("//") => rule.to_lowercase(),
+                _ => continue,
+            };
+            if let Some(exception) = rule.strip_prefix('!') {
+                list.exceptions.insert(to_ascii(exception).into_owned());
+            } else if let Some(parent) = rule.strip_prefix("*.") {
+                list.wildcards.insert(to_ascii(parent).into_owned());
+            } else {
+                list.rules.insert(to_ascii(&rule).into_owned());
+            }
+        }
+        list
+    }
+
+    /// Reads the list from a file, e.g. a copy of public_suffix_list.dat, compressed or not
+    pub fn from_file(path: &str) -> Result<PublicSuffixList> {
+        let text = compression::read_to_string(path)?;
+        Ok(PublicSuffixList::parse(&text))
+    }
+
+    /// Number of rules in the list
+    pub fn len(&self) -> usize {
+        self.rules.len() + self.wildcards.len() + self.exceptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hash of the rules, the same for the same list whatever order it's in
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        for set in [&self.rules, &self.wildcards, &self.exceptions] {
+            let mut rules: Vec<&String> = set.iter().collect();
+            rules.sort_unstable();
+            rules.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Checks if the domain is a public suffix, e.g. com, co.uk or github.io
+    pub fn is_public_suffix(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.');
+        if self.exceptions.contains(domain) {
+            return false;
+        }
+        match domain.split_once('.') {
+            None => true,
+            Some((_, parent)) => self.rules.contains(domain) || self.wildcards.contains(parent),
+        }
+    }
+
+    /// The registrable domain (eTLD+1) of a domain, e.g. example.co.uk for
+    /// www.example.co.uk, None for a public suffix
+    pub fn registrable_domain<'d>(&self, domain: &'d str) -> Option<&'d str> {
+        let domain = domain.trim_end_matches('.');
+        let mut registrable = None;
+        let mut suffix = domain;
+        loop {
+            if self.exceptions.contains(suffix) {
+                return Some(suffix);
+            }
+            if self.is_public_suffix(suffix) {
+                return registrable;
+            }
+            registrable = Some(suffix);
+            suffix = suffix.split_once('.')?.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_public_suffix {
+    use super::*;
+
+    #[test]
+    fn registrable_domain_test() {
+        let list = PublicSuffixList::parse(indoc::indoc! {"
+            // ===BEGIN ICANN DOMAINS===
+            com
+            co.uk
+            *.ck
+            !www.ck
+            // ===BEGIN PRIVATE DOMAINS===
+            github.io
+            公司.cn
+        "});
+        assert_eq!(6, list.len());
+        assert!(list.is_public_suffix("xn--55qx5d.cn"));
+        assert_eq!(
+            Some("xn--fiq228c.xn--55qx5d.cn"),
+            list.registrable_domain("www.xn--fiq228c.xn--55qx5d.cn")
+        );
+        assert!(list.is_public_suffix("co.uk"));
+        assert!(list.is_public_suffix("com."));
+        assert!(list.is_public_suffix("anything.ck"));
+        assert!(!list.is_public_suffix("www.ck"));
+        assert!(!list.is_public_suffix("ads.com"));
+
+        assert_eq!(Some("fb.com"), list.registrable_domain("ads.fb.com"));
+        assert_eq!(Some("bbc.co.uk"), list.registrable_domain("www.bbc.co.uk."));
+        assert_eq!(
+            Some("me.github.io"),
+            list.registrable_domain("me.github.io")
+        );
+        assert_eq!(Some("a.b.ck"), list.registrable_domain("x.a.b.ck"));
+        assert_eq!(Some("www.ck"), list.registrable_domain("a.www.ck"));
+        assert_eq!(Some("example.org"), list.registrable_domain("example.org"));
+        assert_eq!(None, list.registrable_domain("github.io"));
+        assert_eq!(None, list.registrable_domain("org"));
+    }
+}
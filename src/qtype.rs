@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// The record types rules can be restricted to, the position is the bit in QTypes
+const NAMES: [&str; 17] = [
+    "A", "NS", "CNAME", "SOA", "PTR", "MX", "TXT", "AAAA", "SRV", "NAPTR", "DS", "DNSKEY", "SVCB",
+    "HTTPS", "CAA", "SPF", "ANY",
+];
+
+/// A set of DNS record types, e.g. parsed from `$type=HTTPS,SVCB` at the end of a list line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QTypes(u32);
+
+impl QTypes {
+    /// Parses a comma separated list of record type names, case insensitive
+    pub fn parse(s: &str) -> Result<QTypes, String> {
+        let mut types = QTypes::default();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            types.0 |= bit(name).ok_or_else(|| format!("{name}: unknown record type"))?;
+        }
+        if types.is_empty() {
+            return Err(format!("{s}: no record type"));
+        }
+        Ok(types)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The set as a bit mask, the way it is stored in a packed index
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u32) -> QTypes {
+        QTypes(bits)
+    }
+
+    /// Checks a record type as found in a query, unknown types are never contained
+    pub fn contains(&self, qtype: &str) -> bool {
+        bit(qtype).map(|b| self.0 & b != 0).unwrap_or(false)
+    }
+
+    pub fn union(&self, other: QTypes) -> QTypes {
+        QTypes(self.0 | other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, name)| *name)
+    }
+}
+
+fn bit(name: &str) -> Option<u32> {
+    NAMES
+        .iter()
+        .position(|n| n.eq_ignore_ascii_case(name))
+        .map(|i| 1 << i)
+}
+
+impl fmt::Display for QTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.iter().collect::<Vec<_>>().join(","))
+    }
+}
+
+#[test]
+fn qtypes_test() {
+    let types = QTypes::parse("https, svcb").unwrap();
+    assert!(types.contains("HTTPS"));
+    assert!(types.contains("svcb"));
+    assert!(!types.contains("A"));
+    assert!(!types.contains("TYPE65"));
+    assert_eq!("SVCB,HTTPS", types.to_string());
+    assert_eq!(
+        "TXT,SVCB,HTTPS",
+        types.union(QTypes::parse("TXT").unwrap()).to_string()
+    );
+    assert!(QTypes::parse("HTTPS,BOGUS").is_err());
+    assert!(QTypes::parse("").is_err());
+}
@@ -0,0 +1,539 @@
+//! Incremental builds: what a build parsed and folded is kept in a state directory,
+//! the next build only parses the lists whose content changed and only folds the
+//! shards whose input changed
+//!
+//! state.json has the content hash of each block list and the fingerprint of the
+//! input of each shard. list-N.bin has what the N-th block list parsed to, as offsets
+//! into its text, and shard-N.bin the entries of the N-th shard, as positions in its
+//! input, so both point into the lists read by this run. Both start with a CRC32 of
+//! the rest of the file, a file that doesn't match it is built again.
+
+use crate::blocklist::{shard_typed_whitelist, BlockList, Parsed, Rejection};
+use crate::domain_set::DomainSet;
+use crate::error::{Error, Result};
+use crate::index::{shard, Index};
+use crate::output;
+use crate::qtype::QTypes;
+use crate::statistics::Statistics;
+use crate::sub_domains::{validate, Domain};
+use crate::writers::{Format, Order};
+use fnv::FnvHashMap as HashMap;
+use fnv::FnvHashSet as HashSet;
+use fnv::FnvHasher;
+use log::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Changes whenever the layout of the files or the parsing of the lists does,
+/// older states are ignored
+const VERSION: u32 = 3;
+const MANIFEST: &str = "state.json";
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    shards: usize,
+    /// hash of the settings everything depends on, the public suffixes
+    settings: u64,
+    lists: Vec<ListState>,
+    /// of the input of each shard
+    fingerprints: Vec<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ListState {
+    name: String,
+    hash: u64,
+}
+
+/// The state directory and what the previous build left in it
+pub(crate) struct State {
+    dir: PathBuf,
+    previous: Manifest,
+}
+
+/// Hash of a list's text, tells if it changed since the previous build
+pub(crate) fn content_hash(text: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl State {
+    /// Opens the directory, creating it if needed. The previous state is only
+    /// used if it was made with the same number of shards and settings
+    pub(crate) fn open(dir: &str, shards: usize, settings: u64) -> Result<State> {
+        fs::create_dir_all(dir).map_err(|e| Error::output(dir, e))?;
+        let path = Path::new(dir).join(MANIFEST);
+        let previous = match fs::read_to_string(&path) {
+            Ok(json) => match serde_json::from_str::<Manifest>(&json) {
+                Ok(m) if m.version == VERSION && m.shards == shards && m.settings == settings => m,
+                Ok(_) => {
+                    info!(
+                        "The build state in {} is for other settings, building from scratch",
+                        dir
+                    );
+                    Manifest::default()
+                }
+                Err(e) => {
+                    warn!("Ignoring the damaged build state {}: {}", path.display(), e);
+                    Manifest::default()
+                }
+            },
+            Err(_) => Manifest::default(),
+        };
+        Ok(State {
+            dir: PathBuf::from(dir),
+            previous,
+        })
+    }
+
+    fn file(&self, kind: &str, n: usize) -> PathBuf {
+        self.dir.join(format!("{}-{}.bin", kind, n))
+    }
+
+    /// What the list parsed to in the previous build if its content didn't change,
+    /// with the upstream lists named in its headers. The sources of the domains
+    /// are relative to the list
+    pub(crate) fn load_list<'a>(
+        &self,
+        list: usize,
+        file: &'a str,
+        text: &'a str,
+        hash: u64,
+    ) -> Result<Option<(Vec<String>, Parsed<'a>)>> {
+        match self.previous.lists.get(list) {
+            Some(previous) if previous.name == file && previous.hash == hash => {}
+            _ => return Ok(None),
+        }
+        let path = self.file("list", list);
+        let Some(bytes) = self.read(&path) else {
+            return Ok(None);
+        };
+        let mut r = Reader {
+            path: &path,
+            bytes: &bytes,
+            at: 0,
+        };
+        if r.u64()? != hash {
+            return Ok(None);
+        }
+        let slice = |offset: u32, len: u32| {
+            offset
+                .checked_add(len)
+                .and_then(|end| text.get(offset as usize..end as usize))
+                .ok_or_else(|| damaged(&path))
+        };
+
+        let urls = (0..r.u32()?)
+            .map(|_| r.string())
+            .collect::<Result<Vec<String>>>()?;
+        let shards = self.previous.shards;
+        let mut parsed = Parsed::new(list, shards, 0);
+        parsed.lines = r.u32()? as usize;
+        for _ in 0..r.u32()? {
+            let (offset, len, dots, qtypes, source, shard) =
+                (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+            let domains = parsed.domains.get_mut(shard as usize);
+            domains.ok_or_else(|| damaged(&path))?.push(Domain {
+                name: slice(offset, len)?,
+                dots: dots as usize,
+                source: source as usize,
+                qtypes: Some(QTypes::from_bits(qtypes)).filter(|q| !q.is_empty()),
+            });
+        }
+        for _ in 0..r.u32()? {
+            let (offset, len, source) = (r.u32()?, r.u32()?, r.u32()?);
+            parsed
+                .public_suffixes
+                .push((slice(offset, len)?, source as usize));
+        }
+        for _ in 0..r.u32()? {
+            let (offset, len, line_number) = (r.u32()?, r.u32()?, r.u32()?);
+            let line = slice(offset, len)?;
+            let reason = match Domain::parse(line) {
+                Ok(Some(domain)) => validate(domain.name).err(),
+                Ok(None) => None,
+                Err(reason) => Some(reason),
+            };
+            parsed.rejected.push(Rejection {
+                file,
+                line_number: line_number as usize,
+                line,
+                reason: reason.ok_or_else(|| damaged(&path))?,
+            });
+        }
+        Ok(Some((urls, parsed)))
+    }
+
+    /// Keeps what a list parsed to, given as its chunks, the sources of the list start at first_source
+    pub(crate) fn save_list(
+        &self,
+        list: usize,
+        text: &str,
+        hash: u64,
+        urls: &[String],
+        first_source: usize,
+        chunks: &[&Parsed],
+    ) -> Result<()> {
+        // the names are slices of the text
+        let offset = |s: &str| (s.as_ptr() as usize - text.as_ptr() as usize) as u32;
+        let mut w = Vec::with_capacity(text.len());
+        w.extend_from_slice(&hash.to_le_bytes());
+        put(&mut w, &[urls.len() as u32]);
+        for url in urls {
+            put(&mut w, &[url.len() as u32]);
+            w.extend_from_slice(url.as_bytes());
+        }
+        put(
+            &mut w,
+            &[chunks.iter().map(|p| p.lines).sum::<usize>() as u32],
+        );
+
+        let domains = chunks
+            .iter()
+            .map(|p| p.domains.iter().map(Vec::len).sum::<usize>());
+        put(&mut w, &[domains.sum::<usize>() as u32]);
+        for parsed in chunks {
+            for (shard, domains) in parsed.domains.iter().enumerate() {
+                for d in domains {
+                    put(
+                        &mut w,
+                        &[
+                            offset(d.name),
+                            d.name.len() as u32,
+                            d.dots as u32,
+                            d.qtypes.map(|q| q.bits()).unwrap_or(0),
+                            (d.source - first_source) as u32,
+                            shard as u32,
+                        ],
+                    );
+                }
+            }
+        }
+        let suffixes = chunks.iter().flat_map(|p| &p.public_suffixes);
+        put(&mut w, &[suffixes.clone().count() as u32]);
+        for (name, source) in suffixes {
+            let source = (source - first_source) as u32;
+            put(&mut w, &[offset(name), name.len() as u32, source]);
+        }
+        put(
+            &mut w,
+            &[chunks.iter().map(|p| p.rejected.len()).sum::<usize>() as u32],
+        );
+        let mut first_line = 0;
+        for parsed in chunks {
+            for r in &parsed.rejected {
+                let line_number = (first_line + r.line_number) as u32;
+                put(&mut w, &[offset(r.line), r.line.len() as u32, line_number]);
+            }
+            first_line += parsed.lines;
+        }
+        self.write_checked(&self.file("list", list), &w)
+    }
+
+    /// The index of a shard folded by the previous build, if its input is the same
+    pub(crate) fn load_shard<'a>(
+        &self,
+        shard: usize,
+        fingerprint: u64,
+        input: &[&Domain<'a>],
+    ) -> Result<Option<(Index<'a>, Statistics<'a>)>> {
+        if self.previous.fingerprints.get(shard) != Some(&fingerprint) {
+            return Ok(None);
+        }
+        let path = self.file("shard", shard);
+        let Some(bytes) = self.read(&path) else {
+            return Ok(None);
+        };
+        let mut r = Reader {
+            path: &path,
+            bytes: &bytes,
+            at: 0,
+        };
+        if r.u64()? != fingerprint {
+            return Ok(None);
+        }
+        let statistics = serde_json::from_str(&r.string()?).map_err(|_| damaged(&path))?;
+        let name = |position: u32| {
+            let domain = input.get(position as usize);
+            domain.map(|d| d.name).ok_or_else(|| damaged(&path))
+        };
+
+        let blocked = (0..r.u32()?)
+            .map(|_| {
+                let (position, source) = (r.u32()?, r.u32()?);
+                Ok((name(position)?, source as usize))
+            })
+            .collect::<Result<DomainSet<usize>>>()?;
+        let mut index = Index::new(blocked);
+        for _ in 0..r.u32()? {
+            let (position, source) = (r.u32()?, r.u32()?);
+            index.whitelisted.insert(name(position)?, source as usize);
+        }
+        for _ in 0..r.u32()? {
+            let (position, qtypes, source) = (r.u32()?, r.u32()?, r.u32()?);
+            let qtypes = QTypes::from_bits(qtypes);
+            index
+                .typed_blocked
+                .insert(name(position)?, (qtypes, source as usize));
+        }
+        Ok(Some((index, statistics)))
+    }
+
+    /// Keeps the index of a shard folded by this build
+    pub(crate) fn save_shard(
+        &self,
+        shard: usize,
+        fingerprint: u64,
+        input: &[&Domain],
+        index: &Index,
+        statistics: &Statistics,
+    ) -> Result<()> {
+        // the entries are names of the input domains
+        let positions: HashMap<usize, u32> = input
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (d.name.as_ptr() as usize, i as u32))
+            .collect();
+        let position = |name: &str| positions[&(name.as_ptr() as usize)];
+
+        let mut w = Vec::with_capacity(16 + index.blocked.len() * 8);
+        w.extend_from_slice(&fingerprint.to_le_bytes());
+        let json = serde_json::to_string(statistics).map_err(io::Error::from);
+        let json = json.map_err(|e| Error::output(self.dir.to_string_lossy(), e))?;
+        put(&mut w, &[json.len() as u32]);
+        w.extend_from_slice(json.as_bytes());
+        put(&mut w, &[index.blocked.len() as u32]);
+        for (name, source) in index.blocked.iter() {
+            put(&mut w, &[position(name), *source as u32]);
+        }
+        put(&mut w, &[index.whitelisted.len() as u32]);
+        for (name, source) in &index.whitelisted {
+            put(&mut w, &[position(name), *source as u32]);
+        }
+        put(&mut w, &[index.typed_blocked.len() as u32]);
+        for (name, (qtypes, source)) in &index.typed_blocked {
+            put(&mut w, &[position(name), qtypes.bits(), *source as u32]);
+        }
+        self.write_checked(&self.file("shard", shard), &w)
+    }
+
+    /// Writes the manifest, the files it points to have to be saved before
+    pub(crate) fn save(
+        &self,
+        shards: usize,
+        settings: u64,
+        lists: Vec<(String, u64)>,
+        fingerprints: Vec<u64>,
+    ) -> Result<()> {
+        let manifest = Manifest {
+            version: VERSION,
+            shards,
+            settings,
+            lists: lists
+                .into_iter()
+                .map(|(name, hash)| ListState { name, hash })
+                .collect(),
+            fingerprints,
+        };
+        let json = serde_json::to_vec_pretty(&manifest).expect("the manifest is plain data");
+        self.write(&self.dir.join(MANIFEST), &json)
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        output::write_atomic(&path.to_string_lossy(), 0, |f| f.write_all(content))
+    }
+
+    /// Writes a file behind the CRC32 of its content
+    fn write_checked(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let crc = crc32fast::hash(content);
+        output::write_atomic(&path.to_string_lossy(), 0, |f| {
+            f.write_all(&crc.to_le_bytes())?;
+            f.write_all(content)
+        })
+    }
+
+    /// The content of a file written by [`State::write_checked`], None if it's missing
+    /// or doesn't match its CRC32
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        let mut bytes = fs::read(path).ok()?;
+        let crc = bytes.get(..4).and_then(|crc| crc.try_into().ok());
+        if crc.map(u32::from_le_bytes) != Some(crc32fast::hash(bytes.get(4..)?)) {
+            warn!("Ignoring the damaged build state {}", path.display());
+            return None;
+        }
+        bytes.drain(..4);
+        Some(bytes)
+    }
+}
+
+/// Fingerprints of everything the index of each shard is folded from: the settings,
+/// the whitelist entries that fall in it and its domains in the order they are folded in
+pub(crate) fn fingerprints(
+    shards: usize,
+    settings: u64,
+    parsed: &[Parsed],
+    whitelist: &HashSet<&str>,
+    typed_whitelist: &HashMap<&str, (QTypes, usize)>,
+) -> Vec<u64> {
+    let mut whitelisted: Vec<Vec<&str>> = vec![Vec::new(); shards];
+    for name in whitelist {
+        whitelisted[shard(name, shards)].push(name);
+    }
+    whitelisted
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, mut whitelisted)| {
+            let mut hasher = FnvHasher::default();
+            settings.hash(&mut hasher);
+            whitelisted.sort_unstable();
+            whitelisted.hash(&mut hasher);
+            let mut typed: Vec<(&str, u32, usize)> =
+                shard_typed_whitelist(typed_whitelist, |d| shard(d, shards) == i)
+                    .map(|(name, (qtypes, source))| (name, qtypes.bits(), source))
+                    .collect();
+            typed.sort_unstable();
+            typed.hash(&mut hasher);
+            for d in parsed.iter().flat_map(|p| &p.domains[i]) {
+                d.name.hash(&mut hasher);
+                d.qtypes.map(|q| q.bits()).hash(&mut hasher);
+                d.source.hash(&mut hasher);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Checks that an incremental build came out the same as a full one, the packed
+/// format has all the entries with their sources, the unbound one their record types.
+/// Both are compared sorted, unsorted outputs come out in another order from each build
+pub fn verify(incremental: &BlockList, full: &BlockList) -> Result<()> {
+    for format in [Format::Packed, Format::Unbound] {
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        incremental
+            .write_in_order(format, Order::Reversed, &mut a)
+            .and_then(|_| full.write_in_order(format, Order::Reversed, &mut b))
+            .map_err(|e| Error::output("memory", e))?;
+        if a != b {
+            return Err(Error::Suspicious(format!(
+                "the incremental build differs from a full one in the {} format",
+                format
+            )));
+        }
+    }
+    if incremental.statistics().to_string() != full.statistics().to_string() {
+        return Err(Error::Suspicious(
+            "the incremental build has other statistics than a full one".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn put(w: &mut Vec<u8>, words: &[u32]) {
+    for word in words {
+        w.extend_from_slice(&word.to_le_bytes());
+    }
+}
+
+fn damaged(path: &Path) -> Error {
+    Error::InvalidInput(format!("the build state {} is damaged", path.display()))
+}
+
+/// Reads the saved files, an error when they end early
+struct Reader<'b> {
+    path: &'b Path,
+    bytes: &'b [u8],
+    at: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8]> {
+        let bytes = self.bytes;
+        let end = self.at.checked_add(n);
+        let taken = end.and_then(|end| bytes.get(self.at..end));
+        let taken = taken.ok_or_else(|| damaged(self.path))?;
+        self.at += n;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| damaged(self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests_state {
+    use crate::BlockListBuilder;
+    use std::fs;
+
+    #[test]
+    fn incremental_test() {
+        let dir = std::env::temp_dir().join(format!("dns-block-state-{}", std::process::id()));
+        let dir = dir.to_string_lossy().into_owned();
+        let builder = |second: &str| {
+            BlockListBuilder::new()
+                .whitelist_source("white", "good.example\n".to_string())
+                .block_source(
+                    "first",
+                    "ads.fb.com\nx.tracker.net\nbad..name.com\n".to_string(),
+                )
+                .block_source("second", second.to_string())
+                .shards(4)
+                .state_dir(&dir)
+        };
+        let expected = |builder: &BlockListBuilder| {
+            let incremental = builder.build().unwrap();
+            let full = builder.build_from_scratch(&incremental).unwrap();
+            super::verify(&incremental, &full).unwrap();
+            assert_eq!(1, incremental.rejected().len());
+            assert_eq!(full.rejected(), incremental.rejected());
+            let mut entries: Vec<&str> = incremental.entries().collect();
+            entries.sort_unstable();
+            entries.join(",")
+        };
+
+        let first = builder("# dns-block: https://a.example\ntracker.net\nx.ads.fb.com\n");
+        first.build().unwrap();
+        assert_eq!("ads.fb.com,tracker.net", expected(&first));
+        let changed =
+            builder("# dns-block: https://b.example\nfb.org\ntyped.example $type=HTTPS\n");
+        assert_eq!(
+            "ads.fb.com,fb.org,typed.example,x.tracker.net",
+            expected(&changed)
+        );
+        // a damaged state is ignored
+        fs::write(format!("{}/list-1.bin", dir), "damaged").unwrap();
+        assert_eq!(
+            "ads.fb.com,fb.org,typed.example,x.tracker.net",
+            expected(&changed)
+        );
+        // so is one of the right length with other content
+        for file in ["list-0.bin", "shard-0.bin", "shard-1.bin"] {
+            let path = format!("{}/{}", dir, file);
+            let mut bytes = fs::read(&path).unwrap();
+            *bytes.last_mut().unwrap() ^= 0xff;
+            fs::write(&path, bytes).unwrap();
+        }
+        assert_eq!(
+            "ads.fb.com,fb.org,typed.example,x.tracker.net",
+            expected(&changed)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
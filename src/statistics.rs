@@ -1,27 +1,42 @@
+use crate::sub_domains::normalize_name;
+use fnv::FnvHashMap as HashMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt;
 
-#[derive(Debug)]
-pub struct Statistics {
+/// What happened to the domains read from the block lists
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Statistics<'a> {
     parent: usize,
     duplicate: usize,
     whitelisted: usize,
     distinct_whitelisted: usize,
     blocked: usize,
+    public_suffix: usize,
+    /// input lines that are not valid domain names
+    rejected: usize,
+    /// subdomains folded into a blocked parent, by registrable domain, borrowed from
+    /// the input when it outlives the statistics
+    folded: HashMap<Cow<'a, str>, usize>,
 }
 
-impl Statistics {
-    pub fn new() -> Statistics {
-        Statistics {
-            parent: 0,
-            duplicate: 0,
-            whitelisted: 0,
-            distinct_whitelisted: 0,
-            blocked: 0,
-        }
+impl<'a> Statistics<'a> {
+    /// Counts a subdomain of a blocked entry, under its registrable domain
+    pub fn increment_parent(&mut self, registrable: &'a str) {
+        self.parent += 1;
+        *self.folded.entry(Cow::Borrowed(registrable)).or_insert(0) += 1;
     }
 
-    pub fn increment_parent(&mut self) {
+    /// Counts a subdomain of a blocked entry, under a registrable domain that doesn't
+    /// live as long as the statistics, it is copied the first time
+    pub fn increment_parent_copied(&mut self, registrable: &str) {
         self.parent += 1;
+        match self.folded.get_mut(registrable) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(Cow::Owned(registrable.to_string()), 1);
+            }
+        }
     }
 
     pub fn increment_duplicate(&mut self) {
@@ -40,20 +55,44 @@ impl Statistics {
         self.blocked += 1;
     }
 
-    pub fn aggregate(stat1: &Statistics, stat2: &Statistics) -> Statistics {
-        Statistics {
-            parent: stat1.parent + stat2.parent,
-            duplicate: stat1.duplicate + stat2.duplicate,
-            whitelisted: stat1.whitelisted + stat2.whitelisted,
-            distinct_whitelisted: stat1.distinct_whitelisted + stat2.distinct_whitelisted,
-            blocked: stat1.blocked + stat2.blocked,
+    /// Counts the entries refused for being public suffixes
+    pub fn add_public_suffixes(&mut self, count: usize) {
+        self.public_suffix += count;
+    }
+
+    /// Counts the input lines rejected for not being valid domain names
+    pub fn add_rejected(&mut self, count: usize) {
+        self.rejected += count;
+    }
+
+    /// The registrable domains with the most subdomains folded into a blocked parent
+    pub fn top_folded(&self, n: usize) -> Vec<(&str, usize)> {
+        top_n(&self.folded, n, |c| *c)
+    }
+
+    /// Adds the counters of another part of the input, e.g. of a shard
+    pub fn merge(&mut self, other: &Statistics<'a>) {
+        self.parent += other.parent;
+        self.duplicate += other.duplicate;
+        self.whitelisted += other.whitelisted;
+        self.distinct_whitelisted += other.distinct_whitelisted;
+        self.blocked += other.blocked;
+        self.public_suffix += other.public_suffix;
+        self.rejected += other.rejected;
+        for (domain, count) in &other.folded {
+            *self.folded.entry(domain.clone()).or_insert(0) += count;
         }
     }
 }
 
-impl fmt::Display for Statistics {
+impl fmt::Display for Statistics<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let total = self.parent + self.duplicate + self.whitelisted + self.blocked;
+        let total = self.parent
+            + self.duplicate
+            + self.whitelisted
+            + self.blocked
+            + self.public_suffix
+            + self.rejected;
         let pct = |x: usize| x as f32 * 100.0 / total as f32;
         write!(
             f,
@@ -63,6 +102,8 @@ impl fmt::Display for Statistics {
                 Whitelisted: {:>7} {:>6.2}%
                 White(dist): {:>7}
                 Blocked:     {:>7} {:>6.2}%
+                Suffixes:    {:>7} {:>6.2}%
+                Rejected:    {:>7} {:>6.2}%
                 Total:       {:>7} 100.00%
             "},
             self.parent,
@@ -74,11 +115,172 @@ impl fmt::Display for Statistics {
             self.distinct_whitelisted,
             self.blocked,
             pct(self.blocked),
+            self.public_suffix,
+            pct(self.public_suffix),
+            self.rejected,
+            pct(self.rejected),
             total
         )
     }
 }
 
+/// Running counters of the queries seen in pipe mode
+#[derive(Debug, Default)]
+pub struct QueryStatistics {
+    /// number of entries shown in each table
+    top: usize,
+    /// client -> (queries, blocked queries)
+    clients: HashMap<String, (usize, usize)>,
+    blocked_domains: HashMap<String, usize>,
+    allowed_domains: HashMap<String, usize>,
+}
+
+impl QueryStatistics {
+    pub fn new(top: usize) -> QueryStatistics {
+        QueryStatistics {
+            top,
+            ..Default::default()
+        }
+    }
+
+    /// Counts a query, the domain the way the lists have it so the same name in
+    /// another case or with its final dot is the same row
+    pub fn increment(&mut self, client: &str, domain: &str, blocked: bool) {
+        let domain = normalize_name(domain);
+        let counters = match self.clients.get_mut(client) {
+            Some(counters) => counters,
+            None => self.clients.entry(client.to_string()).or_default(),
+        };
+        counters.0 += 1;
+        let domains = if blocked {
+            counters.1 += 1;
+            &mut self.blocked_domains
+        } else {
+            &mut self.allowed_domains
+        };
+        match domains.get_mut(&*domain) {
+            Some(count) => *count += 1,
+            None => {
+                domains.insert(domain.into_owned(), 1);
+            }
+        }
+    }
+}
+
+/// What replaying a query log against a new block list would change
+#[derive(Debug, Default)]
+pub struct AuditStatistics {
+    /// number of entries shown in each table
+    top: usize,
+    queries: usize,
+    newly_allowed: usize,
+    /// client -> (queries, newly blocked queries)
+    clients: HashMap<String, (usize, usize)>,
+    /// newly blocked domain -> queries
+    newly_blocked: HashMap<String, usize>,
+}
+
+impl AuditStatistics {
+    pub fn new(top: usize) -> AuditStatistics {
+        AuditStatistics {
+            top,
+            ..Default::default()
+        }
+    }
+
+    pub fn increment(&mut self, client: &str, domain: &str, was_blocked: bool, blocked: bool) {
+        self.queries += 1;
+        let counters = match self.clients.get_mut(client) {
+            Some(counters) => counters,
+            None => self.clients.entry(client.to_string()).or_default(),
+        };
+        counters.0 += 1;
+        if blocked && !was_blocked {
+            counters.1 += 1;
+            match self.newly_blocked.get_mut(domain) {
+                Some(count) => *count += 1,
+                None => {
+                    self.newly_blocked.insert(domain.to_string(), 1);
+                }
+            }
+        } else if was_blocked && !blocked {
+            self.newly_allowed += 1;
+        }
+    }
+}
+
+/// Returns the n entries with the highest counts, ties in alphabetical order
+fn top_n<K: AsRef<str>, T: Copy>(
+    map: &HashMap<K, T>,
+    n: usize,
+    key: impl Fn(&T) -> usize,
+) -> Vec<(&str, T)> {
+    let mut entries: Vec<(&str, T)> = map.iter().map(|(k, v)| (k.as_ref(), *v)).collect();
+    entries.sort_unstable_by(|a, b| key(&b.1).cmp(&key(&a.1)).then(a.0.cmp(b.0)));
+    entries.truncate(n);
+    entries
+}
+
+impl fmt::Display for QueryStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pct = |x: usize, total: usize| x as f32 * 100.0 / total.max(1) as f32;
+        writeln!(
+            f,
+            "{:<40} {:>9} {:>9} {:>7}",
+            "Client", "Queries", "Blocked", "Pct"
+        )?;
+        for (client, (queries, blocked)) in top_n(&self.clients, self.top, |c| c.1) {
+            writeln!(
+                f,
+                "{:<40} {:>9} {:>9} {:>6.2}%",
+                client,
+                queries,
+                blocked,
+                pct(blocked, queries)
+            )?;
+        }
+        for (title, domains) in [
+            ("Blocked domain", &self.blocked_domains),
+            ("Allowed domain", &self.allowed_domains),
+        ] {
+            writeln!(f, "\n{:<50} {:>9}", title, "Queries")?;
+            for (domain, count) in top_n(domains, self.top, |c| *c) {
+                writeln!(f, "{:<50} {:>9}", domain, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AuditStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let newly_blocked: usize = self.newly_blocked.values().sum();
+        write!(
+            f,
+            indoc::indoc! {"
+                Queries replayed:          {:>9}
+                Allowed -> blocked:        {:>9}
+                Distinct domains:          {:>9}
+                Blocked -> allowed:        {:>9}
+            "},
+            self.queries,
+            newly_blocked,
+            self.newly_blocked.len(),
+            self.newly_allowed,
+        )?;
+        writeln!(f, "\n{:<40} {:>9} {:>9}", "Client", "Queries", "Blocked")?;
+        let affected = top_n(&self.clients, self.top, |c| c.1);
+        for (client, (queries, blocked)) in affected.into_iter().filter(|c| c.1 .1 > 0) {
+            writeln!(f, "{:<40} {:>9} {:>9}", client, queries, blocked)?;
+        }
+        writeln!(f, "\n{:<50} {:>9}", "Newly blocked domain", "Queries")?;
+        for (domain, count) in top_n(&self.newly_blocked, self.top, |c| *c) {
+            writeln!(f, "{:<50} {:>9}", domain, count)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests_display {
 
@@ -90,6 +292,9 @@ mod tests_display {
             whitelisted: 301,
             distinct_whitelisted: 5,
             blocked: 401,
+            public_suffix: 0,
+            rejected: 0,
+            folded: Default::default(),
         };
 
         assert_eq!(
@@ -99,9 +304,34 @@ mod tests_display {
                 Whitelisted:     301  29.98%
                 White(dist):       5
                 Blocked:         401  39.94%
+                Suffixes:          0   0.00%
+                Rejected:          0   0.00%
                 Total:          1004 100.00%
             "},
             format!("{}", s)
         );
     }
+
+    #[test]
+    fn query_statistics_test() {
+        let mut s = super::QueryStatistics::new(1);
+        s.increment("10.0.0.30", "ads.fb.com", true);
+        s.increment("10.0.0.30", "Ads.FB.com.", true);
+        s.increment("10.0.0.30", "mydomain.com", false);
+        s.increment("10.0.0.31", "tracker.com", true);
+
+        assert_eq!(
+            indoc::indoc! {"
+                Client                                     Queries   Blocked     Pct
+                10.0.0.30                                        3         2  66.67%
+
+                Blocked domain                                       Queries
+                ads.fb.com                                                 2
+
+                Allowed domain                                       Queries
+                mydomain.com                                               1
+            "},
+            format!("{}", s)
+        );
+    }
 }
@@ -1,3 +1,11 @@
+use crate::qtype::QTypes;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::str::RSplit;
+
+/// Option at the end of a list line restricting the entry to some record types
+const TYPE_OPTION: &str = "$type=";
+
 pub fn count_char_occurences(line: &str, chr: char) -> usize {
     line.chars().filter(|c| *c == chr).count()
 }
@@ -6,23 +14,150 @@ pub fn count_char_occurences(line: &str, chr: char) -> usize {
 pub struct Domain<'a> {
     pub name: &'a str,
     pub dots: usize,
+    /// index of the list the domain was read from
+    pub source: usize,
+    /// the record types the entry applies to, None for all of them
+    pub qtypes: Option<QTypes>,
 }
 
 impl<'a> Domain<'a> {
-    pub fn new(line: &str) -> Option<Domain> {
+    /// The domain of a list line, lines with an invalid `$type=` option have none
+    pub fn new(line: &str) -> Option<Domain<'_>> {
+        Domain::parse(line).ok().flatten()
+    }
+
+    /// The domain of a list line, None for a line without one and the reason for
+    /// a line with an invalid `$type=` option
+    pub fn parse(line: &str) -> Result<Option<Domain<'_>>, &'static str> {
         let comment_stripped = match line.find('#') {
             Some(idx) => &line[0..idx],
             None => line,
         }
         .trim();
-        if let Some(name) = comment_stripped.split_whitespace().rev().next() {
+        let mut tokens = comment_stripped.split_whitespace();
+        let mut last = tokens.next_back();
+        let mut qtypes = None;
+        if let Some(spec) = last.and_then(|t| t.strip_prefix(TYPE_OPTION)) {
+            qtypes = Some(QTypes::parse(spec).map_err(|_| "unknown record type")?);
+            last = tokens.next_back();
+        }
+        // com. is a name but localhost is not
+        if let Some(name) = last.filter(|name| name.contains('.')) {
+            // ads.fb.com. is the same name as ads.fb.com, the index and the writers
+            // only ever see the second form
+            let name = name.strip_suffix('.').unwrap_or(name);
             let dots = count_char_occurences(name, '.');
-            if dots > 0 {
-                return Some(Domain { name, dots });
+            if !name.is_empty() {
+                return Ok(Some(Domain {
+                    name,
+                    dots,
+                    source: 0,
+                    qtypes,
+                }));
             }
         }
-        None
+        Ok(None)
+    }
+}
+
+/// Converts an internationalized domain name to its IDNA A-label form with the
+/// UTS-46 mapping, e.g. bücher.de to xn--bcher-kva.de, names that can't be
+/// converted are returned unchanged
+pub fn to_ascii(name: &str) -> Cow<'_, str> {
+    if name.is_ascii() {
+        return Cow::Borrowed(name);
+    }
+    match idna::domain_to_ascii(name) {
+        Ok(ascii) => Cow::Owned(ascii),
+        Err(_) => Cow::Borrowed(name),
+    }
+}
+
+/// The name of a query the way the lists have it, in lower case A-labels without
+/// the final dot
+pub fn normalize_name(name: &str) -> Cow<'_, str> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if !name.is_ascii() {
+        to_ascii(name)
+    } else if name.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Converts the internationalized names of a list line to their A-label form,
+/// comments are kept as they are
+pub fn normalize_line(line: &str) -> Cow<'_, str> {
+    let (names, comment) = line.split_at(line.find('#').unwrap_or(line.len()));
+    if names.is_ascii() {
+        return Cow::Borrowed(line);
+    }
+    let mut normalized = String::with_capacity(line.len() + 16);
+    let mut rest = names;
+    for token in names.split_whitespace() {
+        let start = rest.find(token).unwrap_or(0);
+        normalized.push_str(&rest[..start]);
+        normalized.push_str(&to_ascii(token));
+        rest = &rest[start + token.len()..];
+    }
+    normalized.push_str(rest);
+    normalized.push_str(comment);
+    Cow::Owned(normalized)
+}
+
+/// Checks a name against the RFC 1035 and RFC 1123 rules for host names, a leading
+/// underscore is allowed in a label for service names like _dmarc
+pub fn validate(name: &str) -> Result<(), &'static str> {
+    if name.contains("://") || name.contains('/') {
+        return Err("a URL, not a domain name");
+    }
+    if !name.is_ascii() {
+        return Err("not a valid internationalized domain name");
+    }
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() > 253 {
+        return Err("longer than 253 characters");
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err("empty label");
+        }
+        if label.len() > 63 {
+            return Err("label longer than 63 characters");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("label starting or ending with a hyphen");
+        }
+        if label[1..].contains('_') {
+            return Err("underscore inside a label");
+        }
+        if !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err("invalid character");
+        }
     }
+    let is_numeric = |label: &str| label.bytes().all(|b| b.is_ascii_digit());
+    if name.split('.').all(is_numeric) {
+        return Err("an IP address, not a domain name");
+    }
+    if name.rsplit('.').next().is_some_and(is_numeric) {
+        return Err("numeric top level domain");
+    }
+    Ok(())
+}
+
+/// The labels of a name from the top level domain down, ads.fb.com. gives com, fb, ads
+pub fn labels(name: &str) -> RSplit<'_, char> {
+    name.strip_suffix('.').unwrap_or(name).rsplit('.')
+}
+
+/// Compares names by their labels from the top level domain down, a domain sorts
+/// right before its subdomains
+pub fn cmp_labels(a: &str, b: &str) -> Ordering {
+    labels(a).cmp(labels(b))
 }
 
 pub fn sub_domain_iterator(domain: &str, min: usize) -> impl Iterator<Item = &str> {
@@ -56,6 +191,7 @@ fn domain_constructor_test() {
         "domain.com # domain and comment",
         "10.0.0.1 domain.com",
         " 10.0.0.1  domain.com # comment",
+        "domain.com.",
     ];
 
     for line in &v {
@@ -64,5 +200,67 @@ fn domain_constructor_test() {
         let d = od.unwrap();
         assert_eq!("domain.com", d.name);
         assert_eq!(1, d.dots);
+        assert!(d.qtypes.is_none());
+    }
+
+    let d = Domain::new("0.0.0.0 domain.com $type=HTTPS,SVCB # no ech").unwrap();
+    assert_eq!("domain.com", d.name);
+    assert_eq!(Some(QTypes::parse("SVCB,HTTPS").unwrap()), d.qtypes);
+    assert!(Domain::new("domain.com $type=BOGUS").is_none());
+    assert_eq!(
+        Err("unknown record type"),
+        Domain::parse("domain.com $type=BOGUS").map(|d| d.is_some())
+    );
+}
+
+#[test]
+fn validate_test() {
+    for name in [
+        "ads.fb.com",
+        "ads.fb.com.",
+        "_dmarc.x-y.com",
+        "1.2.3.4.in-addr.arpa",
+    ] {
+        assert_eq!(Ok(()), validate(name), "{}", name);
+    }
+    for (name, reason) in [
+        ("http://x.com/path", "a URL, not a domain name"),
+        ("0.0.0.0", "an IP address, not a domain name"),
+        ("x.123", "numeric top level domain"),
+        ("ads..com", "empty label"),
+        ("-ads.com", "label starting or ending with a hyphen"),
+        ("ad_s.com", "underscore inside a label"),
+        ("ads!.com", "invalid character"),
+        (
+            "a☃\u{200b}b.com",
+            "not a valid internationalized domain name",
+        ),
+    ] {
+        assert_eq!(Err(reason), validate(name), "{}", name);
     }
+    assert_eq!(
+        Err("label longer than 63 characters"),
+        validate(&format!("{}.com", "a".repeat(64)))
+    );
+    assert_eq!(
+        Err("longer than 253 characters"),
+        validate(&format!("{}com", "a.".repeat(126)))
+    );
+}
+
+#[test]
+fn normalize_line_test() {
+    assert_eq!("xn--bcher-kva.de", to_ascii("Bücher.de"));
+    assert_eq!(
+        "0.0.0.0 xn--bcher-kva.de # Bücher",
+        normalize_line("0.0.0.0 bücher.de # Bücher")
+    );
+    assert_eq!(
+        "ads.xn--80aikifvh.com $type=A",
+        normalize_line("ads.приклад.com $type=A")
+    );
+    assert!(matches!(
+        normalize_line("ads.com # ünïcode"),
+        Cow::Borrowed(_)
+    ));
 }
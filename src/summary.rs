@@ -0,0 +1,86 @@
+use crate::client_filter::ClientFilter;
+use crate::compression;
+use crate::error::{Error, Result};
+use crate::filter::{parse_query, Lookup, QueryResult};
+use crate::statistics::QueryStatistics;
+use log::*;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the refresh thread wakes up to look for a signal
+const POLL: Duration = Duration::from_millis(200);
+
+fn print_table(statistics: &Mutex<QueryStatistics>, title: &str) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    if handle.is_terminal() {
+        // clear the screen so the table refreshes in place
+        handle.write_all(b"\x1b[2J\x1b[H")?;
+    }
+    handle.write_fmt(format_args!(
+        "{}\n\n{}\n",
+        title,
+        statistics.lock().unwrap()
+    ))?;
+    handle.flush()
+}
+
+/// Reads the query log from stdin and keeps counters per client, blocked and allowed domain
+/// The top n table is printed every interval, on SIGUSR1 and at the end of the input
+pub fn summarize(
+    blocklist: &impl Lookup,
+    client_filter: &ClientFilter,
+    top: usize,
+    interval: Duration,
+) -> Result<()> {
+    let statistics = Arc::new(Mutex::new(QueryStatistics::new(top)));
+
+    let refresh = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&refresh))
+    {
+        warn!("Can't refresh the summary on SIGUSR1: {}", e);
+    }
+
+    let printer_statistics = Arc::clone(&statistics);
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        loop {
+            thread::sleep(POLL);
+            if refresh.swap(false, Ordering::Relaxed) || last.elapsed() >= interval {
+                last = Instant::now();
+                if let Err(e) = print_table(&printer_statistics, "Top queries") {
+                    warn!("Could not print the summary: {}", e);
+                }
+            }
+        }
+    });
+
+    let mut stdin =
+        compression::reader(io::stdin().lock()).map_err(|e| Error::input("stdin", e))?;
+    let mut input = String::new();
+    loop {
+        let n = stdin
+            .read_line(&mut input)
+            .map_err(|e| Error::input("stdin", e))?;
+        if n == 0 {
+            return print_table(&statistics, "Final summary")
+                .map_err(|e| Error::output("stdout", e));
+        }
+        if let Some(query) = parse_query(&input) {
+            if client_filter.matches(query.client) {
+                let (result, _) = blocklist.check(query.name, query.qtype);
+                statistics.lock().unwrap().increment(
+                    query.client,
+                    query.name,
+                    result == QueryResult::Blocked,
+                );
+            }
+        }
+
+        input.truncate(0);
+    }
+}
@@ -0,0 +1,418 @@
+//! Serialization of the blocked domains in the formats understood by DNS servers
+
+use crate::compression::{self, Compression};
+use crate::index::Index;
+use crate::packed;
+use crate::qtype::QTypes;
+use crate::sub_domains::cmp_labels;
+use indoc::indoc;
+use log::*;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+/// Output format of a block list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// One domain per line
+    Plain,
+    /// One domain per line, internationalized ones followed by their Unicode form
+    /// in a comment
+    PlainUnicode,
+    /// Bind9 response policy zone
+    #[serde(alias = "rpz")]
+    Bind,
+    /// local-zone statements to include in the server clause of unbound.conf
+    Unbound,
+    /// Binary index that pipe and audit map into memory instead of reading the lists
+    Packed,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "plain-unicode" => Ok(Format::PlainUnicode),
+            "bind" | "rpz" => Ok(Format::Bind),
+            "unbound" => Ok(Format::Unbound),
+            "packed" => Ok(Format::Packed),
+            _ => Err(format!(
+                "{s}: unknown format, use plain, plain-unicode, bind, unbound or packed"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Plain => "plain",
+            Format::PlainUnicode => "plain-unicode",
+            Format::Bind => "bind",
+            Format::Unbound => "unbound",
+            Format::Packed => "packed",
+        })
+    }
+}
+
+/// Order of the domains in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Order {
+    /// As they come out of the index, the fastest but it changes between runs
+    #[default]
+    Unsorted,
+    Alphabetical,
+    /// By the labels from the top level domain down, so a domain sits next to its
+    /// subdomains and siblings
+    Reversed,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Order, String> {
+        match s {
+            "unsorted" => Ok(Order::Unsorted),
+            "alphabetical" => Ok(Order::Alphabetical),
+            "reversed" => Ok(Order::Reversed),
+            _ => Err(format!(
+                "{s}: unknown order, use unsorted, alphabetical or reversed"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Order::Unsorted => "unsorted",
+            Order::Alphabetical => "alphabetical",
+            Order::Reversed => "reversed",
+        })
+    }
+}
+
+impl Order {
+    /// Sorts the entries, the names are unique so the result is always the same
+    fn sort<T>(self, entries: &mut [(&str, T)]) {
+        match self {
+            Order::Unsorted => (),
+            Order::Alphabetical => entries.sort_unstable_by(|a, b| a.0.cmp(b.0)),
+            Order::Reversed => entries.sort_unstable_by(|a, b| cmp_labels(a.0, b.0)),
+        }
+    }
+}
+
+/// The tables of a block list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Blocked,
+    Whitelisted,
+    TypedBlocked,
+    TypedAllowed,
+}
+
+/// Called with the name, the source and the record types of each entry of a table
+pub type Visit<'v> = dyn FnMut(&str, usize, QTypes) -> io::Result<()> + 'v;
+
+/// What the writers read the entries from, the indexes of a block list in memory
+/// or the files of one folded on disk
+pub trait Entries {
+    /// Names of the lists the entries came from
+    fn sources(&self) -> &[String];
+    fn count(&self, table: Table) -> usize;
+    /// Visits the entries of a table in the given order
+    fn for_each(&self, table: Table, order: Order, visit: &mut Visit) -> io::Result<()>;
+}
+
+/// The entries of the shards of a block list in memory
+pub struct Indexes<'i, 'a> {
+    pub indexes: &'i [&'i Index<'a>],
+    pub sources: &'i [String],
+}
+
+impl Entries for Indexes<'_, '_> {
+    fn sources(&self) -> &[String] {
+        self.sources
+    }
+
+    fn count(&self, table: Table) -> usize {
+        self.indexes
+            .iter()
+            .map(|index| match table {
+                Table::Blocked => index.blocked.len(),
+                Table::Whitelisted => index.whitelisted.len(),
+                Table::TypedBlocked => index.typed_blocked.len(),
+                Table::TypedAllowed => index.typed_allowed.len(),
+            })
+            .sum()
+    }
+
+    fn for_each(&self, table: Table, order: Order, visit: &mut Visit) -> io::Result<()> {
+        let mut entries: Vec<(&str, (QTypes, usize))> = match table {
+            Table::Blocked => self
+                .indexes
+                .iter()
+                .flat_map(|i| i.blocked.iter().map(|(d, s)| (d, (QTypes::default(), *s))))
+                .collect(),
+            Table::Whitelisted => self
+                .indexes
+                .iter()
+                .flat_map(|i| {
+                    i.whitelisted
+                        .iter()
+                        .map(|(d, s)| (*d, (QTypes::default(), *s)))
+                })
+                .collect(),
+            Table::TypedBlocked => self
+                .indexes
+                .iter()
+                .flat_map(|i| i.typed_blocked.iter().map(|(d, t)| (*d, *t)))
+                .collect(),
+            Table::TypedAllowed => self
+                .indexes
+                .iter()
+                .flat_map(|i| i.typed_allowed.iter().map(|(d, t)| (*d, *t)))
+                .collect(),
+        };
+        order.sort(&mut entries);
+        for (name, (qtypes, source)) in entries {
+            visit(name, source, qtypes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the blocked domains of the indexes in the given format and order, the packed
+/// format is always sorted and also has the whitelist and the names of the sources
+pub fn write(
+    format: Format,
+    order: Order,
+    indexes: &[&Index],
+    sources: &[String],
+    w: &mut impl Write,
+) -> io::Result<()> {
+    write_entries(format, order, &Indexes { indexes, sources }, w)
+}
+
+/// Writes the entries in the given format and order
+pub fn write_entries(
+    format: Format,
+    order: Order,
+    entries: &dyn Entries,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    match format {
+        Format::Plain => write_plain(entries, order, w)?,
+        Format::PlainUnicode => write_plain_unicode(entries, order, w)?,
+        Format::Bind => write_bind(entries, order, w)?,
+        Format::Unbound => write_unbound(entries, order, w)?,
+        Format::Packed => packed::write(entries, w)?,
+    }
+    warn_typed_entries(entries, format);
+    w.flush()
+}
+
+/// Counts the entries for all record types in a file written in the given format,
+/// compressed or not, reading it a line at a time
+pub fn count_entries(format: Format, path: &str) -> io::Result<usize> {
+    let file = BufReader::new(File::open(path)?);
+    if format == Format::Packed {
+        // only the header and the checksum are read, from the mapped file
+        let (compression, file) = Compression::of(file)?;
+        let len = match compression {
+            Compression::None => packed::PackedIndex::open(path)
+                .map(|index| index.len())
+                .ok(),
+            _ => {
+                let mut data = Vec::new();
+                compression::reader(file)?.read_to_end(&mut data)?;
+                packed::PackedIndex::from_bytes(data)
+                    .map(|index| index.len())
+                    .ok()
+            }
+        };
+        return Ok(len.unwrap_or(0));
+    }
+    let is_entry = |l: &[u8]| match format {
+        Format::Plain | Format::PlainUnicode => !l.trim_ascii().is_empty(),
+        Format::Bind => {
+            let l = l.trim_ascii_end();
+            l.ends_with(b" CNAME .") && !l.starts_with(b"*.")
+        }
+        Format::Unbound => {
+            let l = l.trim_ascii_end();
+            l.starts_with(b"local-zone:") && l.ends_with(b"always_nxdomain")
+        }
+        Format::Packed => unreachable!("counted from the header"),
+    };
+    let mut reader = compression::reader(file)?;
+    let mut line = Vec::new();
+    let mut count = 0;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if is_entry(&line) {
+            count += 1;
+        }
+        line.clear();
+    }
+    Ok(count)
+}
+
+fn write_plain(entries: &dyn Entries, order: Order, f: &mut impl Write) -> io::Result<()> {
+    let eol: [u8; 1] = [10];
+    entries.for_each(Table::Blocked, order, &mut |d, _, _| {
+        f.write_all(d.as_bytes())?;
+        f.write_all(&eol)
+    })
+}
+
+fn write_plain_unicode(entries: &dyn Entries, order: Order, f: &mut impl Write) -> io::Result<()> {
+    entries.for_each(Table::Blocked, order, &mut |d, _, _| {
+        if d.starts_with("xn--") || d.contains(".xn--") {
+            let (unicode, _) = idna::domain_to_unicode(d);
+            writeln!(f, "{} # {}", d, unicode)
+        } else {
+            writeln!(f, "{}", d)
+        }
+    })
+}
+
+fn write_bind(entries: &dyn Entries, order: Order, f: &mut impl Write) -> io::Result<()> {
+    let preamble = indoc! {"
+        $TTL 60
+        @   IN    SOA  localhost. root.localhost.  (
+                2   ; serial 
+                3H  ; refresh 
+                1H  ; retry 
+                1W  ; expiry 
+                1H) ; minimum 
+            IN    NS    localhost.
+    "};
+    let prefix = "*.";
+    let suffix = " CNAME .";
+
+    f.write_all(preamble.as_bytes())?;
+
+    let eol: [u8; 1] = [10];
+    entries.for_each(Table::Blocked, order, &mut |d, _, _| {
+        f.write_all(d.as_bytes())?;
+        f.write_all(suffix.as_bytes())?;
+        f.write_all(&eol)?;
+
+        f.write_all(prefix.as_bytes())?;
+        f.write_all(d.as_bytes())?;
+        f.write_all(suffix.as_bytes())?;
+        f.write_all(&eol)
+    })
+}
+
+/// Entries for some record types become a typetransparent zone with an empty record
+/// for each type, these only cover the name itself, not its subdomains
+fn write_unbound(entries: &dyn Entries, order: Order, f: &mut impl Write) -> io::Result<()> {
+    entries.for_each(Table::Blocked, order, &mut |d, _, _| {
+        writeln!(f, "local-zone: \"{}.\" always_nxdomain", d)
+    })?;
+    entries.for_each(Table::TypedBlocked, order, &mut |d, _, qtypes| {
+        writeln!(f, "local-zone: \"{}.\" typetransparent", d)?;
+        for qtype in qtypes.iter() {
+            match empty_rdata(qtype) {
+                Some(rdata) => writeln!(f, "local-data: \"{}. 60 IN {} {}\"", d, qtype, rdata)?,
+                None => warn!("Can't block only {} records of {} in unbound", qtype, d),
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Record data that answers a query without pointing anywhere
+fn empty_rdata(qtype: &str) -> Option<&'static str> {
+    match qtype {
+        "A" => Some("0.0.0.0"),
+        "AAAA" => Some("::"),
+        // alias mode to the root means the service is not available, RFC 9460
+        "HTTPS" | "SVCB" => Some("0 ."),
+        // null MX, RFC 7505
+        "MX" => Some("0 ."),
+        "TXT" => Some("\"\""),
+        _ => None,
+    }
+}
+
+/// Record type specific entries only work in pipe mode for formats that can't express them
+/// RPZ answers every type from the policy records of a name, so it can't block just some of them
+fn warn_typed_entries(entries: &dyn Entries, format: Format) {
+    // the packed index keeps them for pipe mode
+    if format == Format::Packed {
+        return;
+    }
+    let blocked = entries.count(Table::TypedBlocked);
+    let allowed = entries.count(Table::TypedAllowed);
+    if blocked > 0 && format != Format::Unbound {
+        warn!(
+            "{} record type specific blocked entries left out of the {} output",
+            blocked, format
+        );
+    }
+    if allowed > 0 {
+        warn!(
+            "{} record type specific whitelist entries can't be expressed in the {} output",
+            allowed, format
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests_writers {
+    use super::*;
+
+    #[test]
+    fn unbound_test() {
+        let mut index = Index::new([("ads.fb.com", 0)].into_iter().collect());
+        index
+            .typed_blocked
+            .insert("ech.example.com", (QTypes::parse("HTTPS").unwrap(), 0));
+        let mut out = Vec::new();
+        write(Format::Unbound, Order::Unsorted, &[&index], &[], &mut out).unwrap();
+        assert_eq!(
+            indoc! {r#"
+                local-zone: "ads.fb.com." always_nxdomain
+                local-zone: "ech.example.com." typetransparent
+                local-data: "ech.example.com. 60 IN HTTPS 0 ."
+            "#},
+            String::from_utf8(out).unwrap()
+        );
+        assert_eq!(Format::Bind, "rpz".parse().unwrap());
+        assert!("hosts".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn order_test() {
+        let com = Index::new(
+            ["b.fb.com", "ads.com", "a.fb.com"]
+                .into_iter()
+                .map(|d| (d, 0))
+                .collect(),
+        );
+        let net = Index::new([("a.fb.net", 0)].into_iter().collect());
+        let write_plain = |order| {
+            let mut out = Vec::new();
+            write(Format::Plain, order, &[&com, &net], &[], &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            "a.fb.com\na.fb.net\nads.com\nb.fb.com\n",
+            write_plain(Order::Alphabetical)
+        );
+        assert_eq!(
+            "ads.com\na.fb.com\nb.fb.com\na.fb.net\n",
+            write_plain(Order::Reversed)
+        );
+        assert_eq!(Order::Reversed, "reversed".parse().unwrap());
+    }
+}